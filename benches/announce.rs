@@ -0,0 +1,145 @@
+//! Measures the time and the number of heap allocations it takes to answer an announce, both
+//! for the tracker itself and for the whole HTTP request handling around it.
+//!
+//!     cargo bench --bench announce
+
+extern crate sanka;
+
+use sanka::config::{ListenerConfig, Protocol};
+use sanka::http::{Request, RequestHandler, ResponseBuffer};
+use sanka::response::success::bencode_announce;
+use sanka::tracker::Tracker;
+use sanka::tracker::announce::{Action, Announce, AnnounceResponse};
+use sanka::tracker::info_hash::InfoHash;
+use sanka::tracker::peer_id::PeerId;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Number of peers announcing to the benchmarked swarm.
+const PEERS: usize = 50;
+const ROUNDS: usize = 2000;
+const LARGE_SWARM: usize = 50000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn main() {
+    bench_tracker();
+    bench_large_swarm();
+    bench_http();
+}
+
+/// Announces straight to the tracker, reusing the response.
+fn bench_tracker() {
+    let tracker = Tracker::default();
+    let announces: Vec<_> = (0..PEERS).map(announce).collect();
+    let mut resp = AnnounceResponse::default();
+    run("tracker", || {
+        for a in announces.iter() {
+            tracker.announce(a, &mut resp).unwrap();
+        }
+    });
+}
+
+/// Re-announces to a swarm the size of a popular torrent's, encoding the responses.
+fn bench_large_swarm() {
+    let tracker = Tracker::default();
+    for i in 0..LARGE_SWARM {
+        tracker.announce(&announce(i), &mut AnnounceResponse::default()).unwrap();
+    }
+    let announces: Vec<_> = (0..PEERS).map(announce).collect();
+    let mut resp = AnnounceResponse::default();
+    let mut body = Vec::new();
+    run("large swarm", || {
+        for a in announces.iter() {
+            tracker.announce(a, &mut resp).unwrap();
+            body.clear();
+            bencode_announce(&resp, &mut body);
+        }
+    });
+}
+
+/// Goes through query parsing, the tracker and response encoding like a server worker does.
+fn bench_http() {
+    let handler = RequestHandler {
+        tracker: Arc::new(Tracker::default()),
+        config: ListenerConfig::new(String::from("127.0.0.1:8000"), Protocol::Http),
+        http: Default::default(),
+    };
+    let paths: Vec<_> = (0..PEERS)
+        .map(|i| {
+            format!("/announce?info_hash=%01%02%03%04%05%06%07%08%09%0A%0B%0C%0D%0E%0F%10%11%12\
+                     %13%14&peer_id=-LD0001-{:012}&port=6881&uploaded=0&downloaded=0&left={}\
+                     &compact=1",
+                    i,
+                    i % 2)
+        })
+        .collect();
+    let remote_addr = "127.0.0.1:6881".parse().unwrap();
+    let mut resp = ResponseBuffer::default();
+    run("http", || {
+        for path in paths.iter() {
+            handler.handle(&Request::new(path, remote_addr, &[]), &mut resp);
+        }
+    });
+}
+
+/// Runs one warm up round so the swarm and buffers are in place, then measures re-announces.
+fn run<F: FnMut()>(name: &str, mut round: F) {
+    round();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        round();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let announces = (ROUNDS * PEERS) as f64;
+    let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+    println!("{}: {:.0} ns/announce, {:.2} allocations/announce",
+             name,
+             nanos / announces,
+             allocations as f64 / announces);
+}
+
+fn announce(i: usize) -> Announce {
+    let peer_id = format!("-LD0001-{:012}", i);
+    Announce {
+        info_hash: InfoHash::from_bytes(&[1; 20]).unwrap(),
+        peer_id: PeerId::from_bytes(peer_id.as_bytes()).unwrap(),
+        passkey: None,
+        key: None,
+        ipv4: Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8), 6881)),
+        ipv6: None,
+        ul: 0,
+        dl: 0,
+        left: (i % 2) as u64,
+        action: if i.is_multiple_of(2) { Action::Seeding } else { Action::Leeching },
+        numwant: 25,
+        compact: true,
+    }
+}
@@ -0,0 +1,126 @@
+//! Load generator for the HTTP interface. Opens a number of keep-alive connections and sends
+//! announces over each as fast as the tracker answers, then reports throughput and latency.
+//!
+//!     cargo run --release --example announce_load -- [addr] [connections] [requests]
+//!
+//! `requests` is the number of announces sent per connection.
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let addr = args.get(1).cloned().unwrap_or(String::from("127.0.0.1:8000"));
+    let connections = args.get(2).and_then(|c| c.parse().ok()).unwrap_or(100);
+    let requests = args.get(3).and_then(|r| r.parse().ok()).unwrap_or(1000);
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..connections)
+        .map(|conn| {
+            let addr = addr.clone();
+            thread::spawn(move || run_connection(&addr, conn, requests))
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(connections * requests);
+    let mut failures = 0;
+    for worker in workers {
+        let (l, f) = worker.join().unwrap();
+        latencies.extend(l);
+        failures += f;
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{} announces over {} connections in {:.2}s, {} failed",
+             latencies.len(),
+             connections,
+             secs,
+             failures);
+    println!("Requests/s: {:.0}", latencies.len() as f64 / secs);
+    println!("p50: {:.2}ms", percentile(&latencies, 0.50));
+    println!("p99: {:.2}ms", percentile(&latencies, 0.99));
+    println!("max: {:.2}ms", percentile(&latencies, 1.0));
+}
+
+fn run_connection(addr: &str, conn: usize, requests: usize) -> (Vec<Duration>, usize) {
+    let mut latencies = Vec::with_capacity(requests);
+    let mut failures = 0;
+    let mut stream = None;
+    for i in 0..requests {
+        if stream.is_none() {
+            stream = TcpStream::connect(addr).ok();
+        }
+        let ok = match stream {
+            Some(ref mut s) => {
+                let start = Instant::now();
+                let ok = announce(s, conn, i);
+                latencies.push(start.elapsed());
+                ok
+            }
+            None => false,
+        };
+        if !ok {
+            failures += 1;
+            stream = None;
+        }
+    }
+    (latencies, failures)
+}
+
+/// Sends a single announce and reads the response, each connection announces to its own
+/// torrent with a new peer every time.
+fn announce(stream: &mut TcpStream, conn: usize, i: usize) -> bool {
+    let req = format!("GET /announce?info_hash={}&peer_id=-LD0001-{:012}&port=6881&uploaded=0\
+                       &downloaded=0&left={}&compact=1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                      encode_hash(conn),
+                      i,
+                      i % 2);
+    if stream.write_all(req.as_bytes()).is_err() {
+        return false;
+    }
+
+    let mut resp = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => resp.extend_from_slice(&buf[..n]),
+        }
+        if let Some(end) = find(&resp, b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&resp[..end]).to_lowercase();
+            let len = head.lines()
+                .filter_map(|l| l.strip_prefix("content-length:"))
+                .filter_map(|l| l.trim().parse::<usize>().ok())
+                .next()
+                .unwrap_or(0);
+            if resp.len() >= end + 4 + len {
+                return head.starts_with("http/1.1 200");
+            }
+        }
+    }
+}
+
+fn encode_hash(conn: usize) -> String {
+    let mut hash = String::with_capacity(60);
+    for i in 0..20 {
+        hash.push_str(&format!("%{:02X}", (conn >> ((i % 4) * 8)) as u8 ^ i as u8));
+    }
+    hash
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p) as usize;
+    let d = sorted[idx];
+    d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+}
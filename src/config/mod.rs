@@ -1,12 +1,19 @@
 use time;
 use std;
 use toml::{Table, Value};
+use std::net::IpAddr;
+use tracker::info_hash::InfoHash;
+use private::client::ClientRule;
+use private::modifier::Modifier;
+use private::bonus::BonusFormula;
 
 #[derive(Default)]
 pub struct MainConfig {
     pub tracker: TrackerConfig,
     pub private: PrivateConfig,
     pub http: HttpConfig,
+    pub websocket: Option<WebSocketConfig>,
+    pub events: EventsConfig,
 }
 
 impl MainConfig {
@@ -23,10 +30,19 @@ impl MainConfig {
             .map_or(None, |t| Some(HttpConfig::from_toml(t)))
             .unwrap_or_default();
 
+        let websocket = toml.get("websocket")
+            .map_or(None, WebSocketConfig::from_toml);
+
+        let events = toml.get("events")
+            .map_or(None, |t| Some(EventsConfig::from_toml(t)))
+            .unwrap_or_default();
+
         MainConfig {
             tracker: tracker,
             private: private,
             http: http,
+            websocket: websocket,
+            events: events,
         }
     }
 }
@@ -34,32 +50,167 @@ impl MainConfig {
 #[derive(Clone)]
 pub struct TrackerConfig {
     pub reap_interval: std::time::Duration,
+    /// Peers the reaper looks at before letting announces through again.
+    pub reap_batch_size: usize,
     pub announce_interval: std::time::Duration,
     pub min_announce_interval: std::time::Duration,
     pub min_torrent_update_interval: time::Duration,
     pub min_peer_update_interval: time::Duration,
+    pub hybrid_torrents: Vec<(InfoHash, InfoHash)>,
+    /// Samples of each torrent's counts kept, one taken per reap.
+    pub torrent_history: usize,
+    /// Torrents kept on each leaderboard.
+    pub leaderboard_size: usize,
+    /// Torrents with at most this many seeders and at least `needs_seeders_min_leechers`
+    /// leechers are listed as needing seeders, up to `needs_seeders_size` of them.
+    pub needs_seeders_max_seeders: u64,
+    pub needs_seeders_min_leechers: u64,
+    pub needs_seeders_size: usize,
 }
 
 #[derive(Clone)]
 pub struct PrivateConfig {
     pub flush_interval: std::time::Duration,
+    /// Rows written to the backend at once.
+    pub flush_batch_size: usize,
+    /// Failed flushes are retried after `flush_interval`, doubling up to this many seconds.
+    pub max_flush_backoff: u64,
+    /// Unwritten rows are kept in this file while the backend is down, and on restarts.
+    pub flush_journal: Option<String>,
+    /// Unwritten rows kept in memory before spilling them to the journal, or dropping them
+    /// without one, unless it's 0.
+    pub max_pending_rows: usize,
     pub update_interval: std::time::Duration,
+    /// Rules on which clients may announce, see `ClientRule`.
+    pub clients: Vec<ClientRule>,
+    /// Users below this ratio may only seed once they've downloaded `ratio_grace` bytes.
+    pub min_ratio: f64,
+    pub ratio_grace: u64,
+    /// Users below this ratio are sent a warning message.
+    pub warn_ratio: f64,
+    /// Modifiers applying to every torrent, e.g. for sitewide freeleech.
+    pub modifiers: Vec<Modifier>,
+    /// Users who complete a torrent have to seed it for `hnr_min_seed_time` seconds or up to
+    /// `hnr_min_ratio`, or it's a hit-and-run once they haven't announced for `hnr_grace`
+    /// seconds. Hit-and-runs are only tracked with a minimum seed time or ratio.
+    pub hnr_min_seed_time: u64,
+    pub hnr_min_ratio: f64,
+    pub hnr_grace: u64,
+    /// Users with this many hit-and-runs can't leech, unless it's 0.
+    pub hnr_max: usize,
+    /// Bytes per second of upload above which announces are quarantined, unless it's 0.
+    pub max_upload_rate: u64,
+    /// Quarantined announces kept for review, the oldest are dropped past this.
+    pub max_quarantine: usize,
+    /// Per user limits on torrents leeched at once, IPs using the passkey at once and peers
+    /// seeding the same torrent, each unless it's 0.
+    pub max_leeching: usize,
+    pub max_ips: usize,
+    pub max_seeds_per_torrent: usize,
+    /// Longest gap between seeding announces counted as seed time.
+    pub max_seed_interval: u64,
+    pub bonus: BonusFormula,
+    pub passkey_locations: Vec<PasskeyLocation>,
     pub extra: Option<Table>,
 }
 
 #[derive(Clone)]
 pub struct HttpConfig {
-    pub listen_addr: String
+    pub listeners: Vec<ListenerConfig>,
+    /// Event loop threads per listener.
+    pub workers: usize,
+    /// Open connections allowed per listener, spread over its workers.
+    pub max_connections: usize,
+    pub keep_alive_timeout: std::time::Duration,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_reload_interval: std::time::Duration,
+    /// Who may look at `/torrent/<info_hash>`.
+    pub torrent_stats: Access,
+    /// Who may look at `/leaderboards`.
+    pub leaderboards: Access,
+    /// Who may look at `/needs_seeders`.
+    pub needs_seeders: Access,
+    /// Token admins authenticate with, either as a bearer token or through `?token=`.
+    pub admin_token: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ListenerConfig {
+    /// Either a socket address, or a Unix domain socket path prefixed with `unix:`.
+    pub address: String,
+    pub protocol: Protocol,
+    /// Whether passkeys are required, only has an effect with the private feature enabled.
+    pub private: bool,
+    pub trusted_proxies: ProxyPolicy,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    Http,
+    Https,
+    Udp,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Off,
+    Public,
+    Admin,
+}
+
+/// Where private listeners look for passkeys: the `/<passkey>/<action>` path, a `passkey`
+/// query parameter or an `Authorization: Passkey <passkey>` header.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PasskeyLocation {
+    Path,
+    Query,
+    Header,
+}
+
+/// Which peers are allowed to set the client address through X-Forwarded-For.
+#[derive(Clone)]
+pub enum ProxyPolicy {
+    Never,
+    Always,
+    Only(Vec<IpAddr>),
+}
+
+/// Where swarm events are sent, none of the sinks are enabled by default.
+#[derive(Clone)]
+pub struct EventsConfig {
+    /// File events are appended to as JSON lines.
+    pub jsonl: Option<String>,
+    /// Append-only log of every snatch, as JSON lines.
+    pub snatch_log: Option<String>,
+    /// `http://` URL batches of events are POSTed to.
+    pub webhook: Option<String>,
+    /// Address to serve the event stream on as Server-Sent Events.
+    pub sse_addr: Option<String>,
+    /// Events queued per sink before new ones are dropped.
+    pub queue_size: usize,
+}
+
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    pub listen_addr: String,
 }
 
 impl Default for TrackerConfig {
     fn default() -> TrackerConfig {
         TrackerConfig {
             reap_interval: std::time::Duration::from_secs(120),
+            reap_batch_size: 10000,
             announce_interval: std::time::Duration::from_secs(1800),
             min_announce_interval: std::time::Duration::from_secs(900),
             min_torrent_update_interval: time::Duration::seconds(2000),
             min_peer_update_interval: time::Duration::seconds(2000),
+            hybrid_torrents: Vec::new(),
+            torrent_history: 30,
+            leaderboard_size: 50,
+            needs_seeders_max_seeders: 1,
+            needs_seeders_min_leechers: 1,
+            needs_seeders_size: 100,
         }
     }
 }
@@ -76,24 +227,54 @@ impl TrackerConfig {
                     .map_or(None, |v| v.as_integer())
                     .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
                     .unwrap_or(std::time::Duration::from_secs(120));
+                let reap_batch_size = t.get("reap_batch_size")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(10000, |v| v as usize);
                 let min_announce_interval = t.get("min_announce_interval")
                     .map_or(None, |v| v.as_integer())
                     .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
                     .unwrap_or(std::time::Duration::from_secs(900));
                 let min_torrent_update_interval = t.get("min_torrent_update_interval")
                     .map_or(None, |v| v.as_integer())
-                    .map_or(None, |v| Some(time::Duration::seconds(v as i64)))
+                    .map_or(None, |v| Some(time::Duration::seconds(v)))
                     .unwrap_or(time::Duration::seconds(900));
                 let min_peer_update_interval = t.get("min_peer_update_interval")
                     .map_or(None, |v| v.as_integer())
-                    .map_or(None, |v| Some(time::Duration::seconds(v as i64)))
+                    .map_or(None, |v| Some(time::Duration::seconds(v)))
                     .unwrap_or(time::Duration::seconds(900));
+                let hybrid_torrents = t.get("hybrid_torrents")
+                    .map_or(None, |v| v.as_slice())
+                    .map_or(Vec::new(), |pairs| {
+                        pairs.iter().filter_map(parse_hybrid_pair).collect()
+                    });
+                let torrent_history = t.get("torrent_history")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(30, |v| v as usize);
+                let leaderboard_size = t.get("leaderboard_size")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(50, |v| v as usize);
+                let needs_seeders_max_seeders = t.get("needs_seeders_max_seeders")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(1, |v| v as u64);
+                let needs_seeders_min_leechers = t.get("needs_seeders_min_leechers")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(1, |v| v as u64);
+                let needs_seeders_size = t.get("needs_seeders_size")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(100, |v| v as usize);
                 TrackerConfig {
                     reap_interval: reap_interval,
+                    reap_batch_size: reap_batch_size,
                     announce_interval: announce_interval,
                     min_announce_interval: min_announce_interval,
                     min_torrent_update_interval: min_torrent_update_interval,
                     min_peer_update_interval: min_peer_update_interval,
+                    hybrid_torrents: hybrid_torrents,
+                    torrent_history: torrent_history,
+                    leaderboard_size: leaderboard_size,
+                    needs_seeders_max_seeders: needs_seeders_max_seeders,
+                    needs_seeders_min_leechers: needs_seeders_min_leechers,
+                    needs_seeders_size: needs_seeders_size,
                 }
             }
             _ => Default::default()
@@ -101,11 +282,75 @@ impl TrackerConfig {
     }
 }
 
+fn parse_hybrid_pair(pair: &Value) -> Option<(InfoHash, InfoHash)> {
+    let hashes: Vec<_> = pair.as_slice()
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|v| v.as_str())
+        .filter_map(InfoHash::from_hex)
+        .collect();
+    if hashes.len() == 2 {
+        Some((hashes[0], hashes[1]))
+    } else {
+        warn!("Ignoring malformed hybrid torrent entry {:?}!", pair);
+        None
+    }
+}
+
+fn parse_client_rule(rule: &Value) -> Option<ClientRule> {
+    let parsed = rule.as_str().and_then(ClientRule::parse);
+    if parsed.is_none() {
+        warn!("Ignoring malformed client rule {:?}!", rule);
+    }
+    parsed
+}
+
+fn parse_modifier(modifier: &Value) -> Option<Modifier> {
+    let parsed = Modifier::from_toml(modifier);
+    if parsed.is_none() {
+        warn!("Ignoring malformed modifier {:?}!", modifier);
+    }
+    parsed
+}
+
+fn parse_passkey_location(location: &Value) -> Option<PasskeyLocation> {
+    match location.as_str() {
+        Some("path") => Some(PasskeyLocation::Path),
+        Some("query") => Some(PasskeyLocation::Query),
+        Some("header") => Some(PasskeyLocation::Header),
+        _ => {
+            warn!("Ignoring unknown passkey location {:?}!", location);
+            None
+        }
+    }
+}
+
 impl Default for PrivateConfig {
     fn default() -> PrivateConfig {
         PrivateConfig {
             flush_interval: std::time::Duration::from_secs(5),
             update_interval: std::time::Duration::from_secs(900),
+            clients: Vec::new(),
+            min_ratio: 0.0,
+            ratio_grace: 0,
+            warn_ratio: 0.0,
+            modifiers: Vec::new(),
+            hnr_min_seed_time: 0,
+            hnr_min_ratio: 0.0,
+            hnr_grace: 86400,
+            hnr_max: 0,
+            max_upload_rate: 0,
+            max_leeching: 0,
+            max_ips: 0,
+            max_seeds_per_torrent: 0,
+            max_seed_interval: 3600,
+            max_quarantine: 10000,
+            bonus: Default::default(),
+            passkey_locations: vec![PasskeyLocation::Path],
+            flush_batch_size: 1000,
+            max_flush_backoff: 300,
+            flush_journal: None,
+            max_pending_rows: 100000,
             extra: None,
         }
     }
@@ -119,13 +364,104 @@ impl PrivateConfig {
                     .map_or(None, |v| v.as_integer())
                     .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
                     .unwrap_or(std::time::Duration::from_secs(5));
+                let flush_batch_size = t.get("flush_batch_size")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(1000) as usize;
+                let max_flush_backoff = t.get("max_flush_backoff")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(300) as u64;
+                let flush_journal = t.get("flush_journal")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let max_pending_rows = t.get("max_pending_rows")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(100000) as usize;
                 let update_interval = t.get("update_interval")
                     .map_or(None, |v| v.as_integer())
                     .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
                     .unwrap_or(std::time::Duration::from_secs(900));
+                let clients = t.get("clients")
+                    .map_or(None, |v| v.as_slice())
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(parse_client_rule)
+                    .collect();
+                let min_ratio = t.get("min_ratio")
+                    .map_or(None, |v| v.as_float())
+                    .unwrap_or(0.0);
+                let ratio_grace = t.get("ratio_grace")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as u64;
+                let warn_ratio = t.get("warn_ratio")
+                    .map_or(None, |v| v.as_float())
+                    .unwrap_or(min_ratio);
+                let modifiers = t.get("modifiers")
+                    .map_or(None, |v| v.as_slice())
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(parse_modifier)
+                    .collect();
+                let hnr_min_seed_time = t.get("hnr_min_seed_time")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as u64;
+                let hnr_min_ratio = t.get("hnr_min_ratio")
+                    .map_or(None, |v| v.as_float())
+                    .unwrap_or(0.0);
+                let hnr_grace = t.get("hnr_grace")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(86400) as u64;
+                let hnr_max = t.get("hnr_max")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as usize;
+                let max_upload_rate = t.get("max_upload_rate")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as u64;
+                let max_leeching = t.get("max_leeching")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as usize;
+                let max_ips = t.get("max_ips")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as usize;
+                let max_seeds_per_torrent = t.get("max_seeds_per_torrent")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(0) as usize;
+                let max_quarantine = t.get("max_quarantine")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(10000) as usize;
+                let max_seed_interval = t.get("max_seed_interval")
+                    .map_or(None, |v| v.as_integer())
+                    .unwrap_or(3600) as u64;
+                let bonus = t.get("bonus")
+                    .map_or(None, |t| Some(BonusFormula::from_toml(t)))
+                    .unwrap_or_default();
+                let passkey_locations = t.get("passkey_locations")
+                    .map_or(None, |v| v.as_slice())
+                    .map_or(vec![PasskeyLocation::Path],
+                            |v| v.iter().filter_map(parse_passkey_location).collect());
                 PrivateConfig {
                     flush_interval: flush_interval,
                     update_interval: update_interval,
+                    clients: clients,
+                    min_ratio: min_ratio,
+                    ratio_grace: ratio_grace,
+                    warn_ratio: warn_ratio,
+                    modifiers: modifiers,
+                    hnr_min_seed_time: hnr_min_seed_time,
+                    hnr_min_ratio: hnr_min_ratio,
+                    hnr_grace: hnr_grace,
+                    hnr_max: hnr_max,
+                    max_upload_rate: max_upload_rate,
+                    max_leeching: max_leeching,
+                    max_ips: max_ips,
+                    max_seeds_per_torrent: max_seeds_per_torrent,
+                    max_seed_interval: max_seed_interval,
+                    max_quarantine: max_quarantine,
+                    bonus: bonus,
+                    passkey_locations: passkey_locations,
+                    flush_batch_size: flush_batch_size,
+                    max_flush_backoff: max_flush_backoff,
+                    flush_journal: flush_journal,
+                    max_pending_rows: max_pending_rows,
                     extra: Some(t.clone())
                 }
             }
@@ -137,7 +473,17 @@ impl PrivateConfig {
 impl Default for HttpConfig {
     fn default() -> HttpConfig {
         HttpConfig {
-            listen_addr: String::from("127.0.0.1:8000")
+            listeners: vec![ListenerConfig::new(String::from("127.0.0.1:8000"), Protocol::Http)],
+            workers: 1,
+            max_connections: 10000,
+            keep_alive_timeout: std::time::Duration::from_secs(15),
+            tls_cert: None,
+            tls_key: None,
+            tls_reload_interval: std::time::Duration::from_secs(60),
+            torrent_stats: Access::Off,
+            leaderboards: Access::Off,
+            needs_seeders: Access::Off,
+            admin_token: None,
         }
     }
 }
@@ -146,15 +492,226 @@ impl HttpConfig {
     fn from_toml(toml: &Value) -> HttpConfig {
         match *toml {
             Value::Table(ref t) => {
-                let listen_addr = t.get("listen_addr")
+                let workers = t.get("workers")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(1, |v| std::cmp::max(v, 1) as usize);
+                let max_connections = t.get("max_connections")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(10000, |v| v as usize);
+                let keep_alive_timeout = t.get("keep_alive_timeout")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
+                    .unwrap_or(std::time::Duration::from_secs(15));
+                let tls_cert = t.get("tls_cert")
                     .map_or(None, |v| v.as_str())
-                    .map_or(None, |v| Some(String::from(v)))
-                    .unwrap_or(String::from("127.0.0.1:8000"));
+                    .map(String::from);
+                let tls_key = t.get("tls_key")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let tls_reload_interval = t.get("tls_reload_interval")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(None, |v| Some(std::time::Duration::from_secs(v as u64)))
+                    .unwrap_or(std::time::Duration::from_secs(60));
+                let torrent_stats = Access::from_toml(t.get("torrent_stats"));
+                let leaderboards = Access::from_toml(t.get("leaderboards"));
+                let needs_seeders = Access::from_toml(t.get("needs_seeders"));
+                let admin_token = t.get("admin_token")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                // A lone listen_addr is kept working as a single listener, serving HTTPS if a
+                // certificate is configured.
+                let listeners = match t.get("listeners").map_or(None, |v| v.as_slice()) {
+                    Some(listeners) => {
+                        listeners.iter().filter_map(ListenerConfig::from_toml).collect()
+                    }
+                    None => {
+                        let listen_addr = t.get("listen_addr")
+                            .map_or(None, |v| v.as_str())
+                            .map_or(None, |v| Some(String::from(v)))
+                            .unwrap_or(String::from("127.0.0.1:8000"));
+                        let protocol = if tls_cert.is_some() && tls_key.is_some() {
+                            Protocol::Https
+                        } else {
+                            Protocol::Http
+                        };
+                        let mut listener = ListenerConfig::new(listen_addr, protocol);
+                        listener.trusted_proxies =
+                            ProxyPolicy::from_toml(t.get("trusted_proxies"), &listener.address);
+                        vec![listener]
+                    }
+                };
                 HttpConfig {
-                    listen_addr: listen_addr,
+                    listeners: listeners,
+                    workers: workers,
+                    max_connections: max_connections,
+                    keep_alive_timeout: keep_alive_timeout,
+                    tls_cert: tls_cert,
+                    tls_key: tls_key,
+                    tls_reload_interval: tls_reload_interval,
+                    torrent_stats: torrent_stats,
+                    leaderboards: leaderboards,
+                    needs_seeders: needs_seeders,
+                    admin_token: admin_token,
+                }
+            }
+            _ => Default::default()
+        }
+    }
+}
+
+impl Access {
+    fn from_toml(toml: Option<&Value>) -> Access {
+        match toml.map_or(None, |v| v.as_str()) {
+            Some("public") => Access::Public,
+            Some("admin") => Access::Admin,
+            _ => Access::Off,
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> EventsConfig {
+        EventsConfig {
+            jsonl: None,
+            snatch_log: None,
+            webhook: None,
+            sse_addr: None,
+            queue_size: 100000,
+        }
+    }
+}
+
+impl EventsConfig {
+    fn from_toml(toml: &Value) -> EventsConfig {
+        match *toml {
+            Value::Table(ref t) => {
+                let jsonl = t.get("jsonl")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let webhook = t.get("webhook")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let snatch_log = t.get("snatch_log")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let sse_addr = t.get("sse_addr")
+                    .map_or(None, |v| v.as_str())
+                    .map(String::from);
+                let queue_size = t.get("queue_size")
+                    .map_or(None, |v| v.as_integer())
+                    .map_or(100000, |v| std::cmp::max(v, 1) as usize);
+                EventsConfig {
+                    jsonl: jsonl,
+                    snatch_log: snatch_log,
+                    webhook: webhook,
+                    sse_addr: sse_addr,
+                    queue_size: queue_size,
                 }
             }
             _ => Default::default()
         }
     }
 }
+
+impl ListenerConfig {
+    pub fn new(address: String, protocol: Protocol) -> ListenerConfig {
+        ListenerConfig {
+            trusted_proxies: ProxyPolicy::from_toml(None, &address),
+            address: address,
+            protocol: protocol,
+            private: true,
+        }
+    }
+
+    fn from_toml(toml: &Value) -> Option<ListenerConfig> {
+        let t = match toml.as_table() {
+            Some(t) => t,
+            None => return None,
+        };
+        let address = match t.get("address").map_or(None, |v| v.as_str()) {
+            Some(address) => String::from(address),
+            None => {
+                warn!("Ignoring listener without an address!");
+                return None;
+            }
+        };
+        let protocol = match t.get("protocol").map_or(Some("http"), |v| v.as_str()) {
+            Some("http") => Protocol::Http,
+            Some("https") => Protocol::Https,
+            Some("udp") => Protocol::Udp,
+            _ => {
+                warn!("Ignoring listener {} with an unknown protocol!", address);
+                return None;
+            }
+        };
+        let private = t.get("private")
+            .map_or(None, |v| v.as_bool())
+            .unwrap_or(true);
+        let trusted_proxies = ProxyPolicy::from_toml(t.get("trusted_proxies"), &address);
+        Some(ListenerConfig {
+            address: address,
+            protocol: protocol,
+            private: private,
+            trusted_proxies: trusted_proxies,
+        })
+    }
+
+    pub fn unix_path(&self) -> Option<&str> {
+        if self.address.starts_with("unix:") {
+            Some(&self.address[5..])
+        } else {
+            None
+        }
+    }
+}
+
+impl ProxyPolicy {
+    /// Parses `"none"`, `"all"` or a list of addresses, nobody being trusted by default.
+    /// Unix socket peers are always the proxy in front, whose header is all there is to go by.
+    fn from_toml(toml: Option<&Value>, address: &str) -> ProxyPolicy {
+        if address.starts_with("unix:") {
+            match toml {
+                None => {}
+                Some(Value::String(policy)) if policy == "all" => {}
+                Some(_) => warn!("Unix socket listener {} always trusts X-Forwarded-For!", address),
+            }
+            return ProxyPolicy::Always;
+        }
+        match toml {
+            None => ProxyPolicy::Never,
+            Some(Value::String(policy)) if policy == "none" => ProxyPolicy::Never,
+            Some(Value::String(policy)) if policy == "all" => ProxyPolicy::Always,
+            Some(Value::Array(ips)) => {
+                ProxyPolicy::Only(ips.iter()
+                    .filter_map(|v| v.as_str())
+                    .filter_map(|v| v.parse().ok())
+                    .collect())
+            }
+            Some(policy) => {
+                warn!("Ignoring unknown trusted_proxies {:?} of listener {}!", policy, address);
+                ProxyPolicy::Never
+            }
+        }
+    }
+
+    pub fn trusts(&self, ip: &IpAddr) -> bool {
+        match *self {
+            ProxyPolicy::Never => false,
+            ProxyPolicy::Always => true,
+            ProxyPolicy::Only(ref ips) => ips.contains(ip),
+        }
+    }
+}
+
+impl WebSocketConfig {
+    fn from_toml(toml: &Value) -> Option<WebSocketConfig> {
+        toml.as_table()
+            .map_or(None, |t| t.get("listen_addr"))
+            .map_or(None, |v| v.as_str())
+            .map(|v| {
+                WebSocketConfig {
+                    listen_addr: String::from(v),
+                }
+            })
+    }
+}
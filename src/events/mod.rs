@@ -0,0 +1,350 @@
+mod webhook;
+mod sse;
+
+pub use self::webhook::WebhookSink;
+pub use self::sse::SseSink;
+
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use tracker::peer::Suspicion;
+use config::EventsConfig;
+
+use parking_lot::RwLock;
+use rustc_serialize::json::{Json, Object};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use time;
+
+/// Most events a sink is handed at once.
+const MAX_BATCH: usize = 1000;
+
+/// Something that happened in a swarm, stamped with the Unix time it happened at.
+#[derive(Clone, Debug)]
+pub struct SwarmEvent {
+    pub time: i64,
+    pub info_hash: InfoHash,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    TorrentCreated,
+    TorrentReaped,
+    PeerStarted(PeerId),
+    PeerStopped(PeerId),
+    PeerCompleted(PeerId),
+    /// A completed download counted towards the torrent's snatches, which are given as of
+    /// after this one.
+    Snatch {
+        peer_id: PeerId,
+        passkey: Option<String>,
+        ip: Option<IpAddr>,
+        snatches: i64,
+    },
+    /// An announce quarantined for reporting an unlikely transfer.
+    Suspicious {
+        peer_id: PeerId,
+        passkey: Option<String>,
+        upload: u64,
+        download: u64,
+        suspicions: Vec<Suspicion>,
+    },
+}
+
+/// A destination for swarm events. Every sink runs on its own thread, so it's free to block.
+pub trait EventSink: Send {
+    /// Handles events in the order they happened, in batches of whatever queued up meanwhile.
+    fn handle(&mut self, events: &[SwarmEvent]);
+}
+
+/// Hands events out to the registered sinks. Each sink has a bounded queue, events are
+/// dropped rather than holding up announces when a sink falls behind.
+#[derive(Default)]
+pub struct EventBus {
+    enabled: AtomicBool,
+    queues: RwLock<Vec<Queue>>,
+}
+
+struct Queue {
+    tx: SyncSender<SwarmEvent>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Passes events on to a channel, for use within the same process.
+pub struct ChannelSink(mpsc::Sender<SwarmEvent>);
+
+/// Appends events to a file, one JSON object per line.
+pub struct JsonlSink {
+    file: BufWriter<File>,
+    snatches_only: bool,
+}
+
+impl SwarmEvent {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            EventKind::TorrentCreated => "torrent_created",
+            EventKind::TorrentReaped => "torrent_reaped",
+            EventKind::PeerStarted(_) => "peer_started",
+            EventKind::PeerStopped(_) => "peer_stopped",
+            EventKind::PeerCompleted(_) => "peer_completed",
+            EventKind::Snatch { .. } => "snatch",
+            EventKind::Suspicious { .. } => "suspicious",
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut json = Object::new();
+        json.insert(String::from("event"), Json::String(String::from(self.name())));
+        json.insert(String::from("time"), Json::I64(self.time));
+        json.insert(String::from("info_hash"), Json::String(self.info_hash.to_hex()));
+        match self.kind {
+            EventKind::PeerStarted(ref id) |
+            EventKind::PeerStopped(ref id) |
+            EventKind::PeerCompleted(ref id) => {
+                json.insert(String::from("peer_id"), Json::String(id.to_hex()));
+            }
+            EventKind::Snatch { ref peer_id, ref passkey, ip, snatches } => {
+                json.insert(String::from("peer_id"), Json::String(peer_id.to_hex()));
+                if let Some(ref passkey) = *passkey {
+                    json.insert(String::from("passkey"), Json::String(passkey.clone()));
+                }
+                if let Some(ip) = ip {
+                    json.insert(String::from("ip"), Json::String(ip.to_string()));
+                }
+                json.insert(String::from("snatches"), Json::I64(snatches));
+            }
+            EventKind::Suspicious { ref peer_id,
+                                    ref passkey,
+                                    upload,
+                                    download,
+                                    ref suspicions } => {
+                json.insert(String::from("peer_id"), Json::String(peer_id.to_hex()));
+                if let Some(ref passkey) = *passkey {
+                    json.insert(String::from("passkey"), Json::String(passkey.clone()));
+                }
+                json.insert(String::from("upload"), Json::U64(upload));
+                json.insert(String::from("download"), Json::U64(download));
+                let reasons = suspicions.iter()
+                    .map(|s| Json::String(String::from(s.name())))
+                    .collect();
+                json.insert(String::from("suspicions"), Json::Array(reasons));
+            }
+            _ => {}
+        }
+        Json::Object(json)
+    }
+
+    /// The event without passkeys and IPs, for sinks sending it off the tracker's host.
+    pub fn to_public_json(&self) -> Json {
+        let mut json = self.to_json();
+        if let Json::Object(ref mut object) = json {
+            object.remove("passkey");
+            object.remove("ip");
+        }
+        json
+    }
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        Default::default()
+    }
+
+    /// Whether there's anyone to emit events to, so callers can skip building them.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn emit(&self, info_hash: InfoHash, kind: EventKind) {
+        if !self.is_enabled() {
+            return;
+        }
+        let event = SwarmEvent {
+            time: time::get_time().sec,
+            info_hash: info_hash,
+            kind: kind,
+        };
+        for queue in self.queues.read().iter() {
+            if let Err(TrySendError::Full(_)) = queue.tx.try_send(event.clone()) {
+                queue.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Starts handing events to `sink` on a thread of its own, queueing up to `queue_size`.
+    pub fn add_sink<S: EventSink + 'static>(&self, mut sink: S, queue_size: usize) {
+        let (tx, rx) = mpsc::sync_channel(queue_size);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let sink_dropped = dropped.clone();
+        thread::spawn(move || {
+            let mut batch = Vec::new();
+            while let Ok(event) = rx.recv() {
+                batch.push(event);
+                while batch.len() < MAX_BATCH {
+                    match rx.try_recv() {
+                        Ok(event) => batch.push(event),
+                        Err(_) => break,
+                    }
+                }
+                let dropped = sink_dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("Event sink fell behind, dropped {} events!", dropped);
+                }
+                sink.handle(&batch);
+                batch.clear();
+            }
+        });
+        self.queues.write().push(Queue {
+            tx: tx,
+            dropped: dropped,
+        });
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns a channel receiving every event from here on.
+    pub fn subscribe(&self, queue_size: usize) -> Receiver<SwarmEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.add_sink(ChannelSink(tx), queue_size);
+        rx
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn handle(&mut self, events: &[SwarmEvent]) {
+        for event in events {
+            // Nobody listening anymore is no reason to stop the tracker.
+            let _ = self.0.send(event.clone());
+        }
+    }
+}
+
+impl JsonlSink {
+    pub fn new(path: &str) -> io::Result<JsonlSink> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        Ok(JsonlSink {
+            file: BufWriter::new(file),
+            snatches_only: false,
+        })
+    }
+
+    /// Only logs snatches, keeping a record of who completed what.
+    pub fn snatches(path: &str) -> io::Result<JsonlSink> {
+        let mut sink = try!(JsonlSink::new(path));
+        sink.snatches_only = true;
+        Ok(sink)
+    }
+}
+
+impl EventSink for JsonlSink {
+    fn handle(&mut self, events: &[SwarmEvent]) {
+        let mut res = Ok(());
+        for event in events {
+            if self.snatches_only && event.name() != "snatch" {
+                continue;
+            }
+            res = res.and_then(|_| writeln!(self.file, "{}", event.to_json()));
+        }
+        if let Err(e) = res.and_then(|_| self.file.flush()) {
+            error!("Failed to write events to file: {}", e);
+        }
+    }
+}
+
+/// Registers the sinks enabled in the config. The event stream needs `admin_token`.
+pub fn start(bus: &EventBus, config: &EventsConfig, admin_token: Option<&String>) {
+    if let Some(ref path) = config.jsonl {
+        match JsonlSink::new(path) {
+            Ok(sink) => bus.add_sink(sink, config.queue_size),
+            Err(e) => error!("Failed to open event file {}: {}", path, e),
+        }
+    }
+    if let Some(ref path) = config.snatch_log {
+        match JsonlSink::snatches(path) {
+            Ok(sink) => bus.add_sink(sink, config.queue_size),
+            Err(e) => error!("Failed to open snatch log {}: {}", path, e),
+        }
+    }
+    if let Some(ref url) = config.webhook {
+        match WebhookSink::new(url) {
+            Some(sink) => bus.add_sink(sink, config.queue_size),
+            None => error!("Unsupported webhook URL {}, only http:// is supported!", url),
+        }
+    }
+    if let Some(ref addr) = config.sse_addr {
+        let token = match admin_token {
+            Some(token) if !token.is_empty() => token.clone(),
+            _ => {
+                error!("The event stream on {} needs an admin_token, not starting it!", addr);
+                return;
+            }
+        };
+        match SseSink::start(addr, token) {
+            Ok(sink) => {
+                info!("Event stream listening on {}!", addr);
+                bus.add_sink(sink, config.queue_size);
+            }
+            Err(e) => error!("Failed to listen for event streams on {}: {}", addr, e),
+        }
+    }
+}
+
+#[test]
+fn announces_emit_events() {
+    use tracker::Tracker;
+    use tracker::announce::{Action, Announce, test_announce};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    let tracker = Tracker::default();
+    let events = tracker.events.subscribe(16);
+    let peer_id = PeerId::from_bytes(&[1; 20]).unwrap();
+    for action in [Action::Leeching, Action::Leeching, Action::Completed, Action::Stopped] {
+        tracker.handle_announce(Announce {
+                left: 0,
+                action: action,
+                numwant: 0,
+                ..test_announce(1, 1)
+            })
+            .unwrap();
+    }
+
+    let kinds: Vec<_> = (0..5)
+        .map(|_| events.recv_timeout(Duration::from_secs(5)).unwrap().kind)
+        .collect();
+    assert!(kinds ==
+            vec![EventKind::TorrentCreated,
+                 EventKind::PeerStarted(peer_id),
+                 EventKind::PeerCompleted(peer_id),
+                 EventKind::Snatch {
+                     peer_id: peer_id,
+                     passkey: None,
+                     ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+                     snatches: 1,
+                 },
+                 EventKind::PeerStopped(peer_id)]);
+}
+
+#[test]
+fn public_events_leave_out_users() {
+    use std::net::Ipv4Addr;
+
+    let event = SwarmEvent {
+        time: 0,
+        info_hash: InfoHash::from_bytes(&[0; 20]).unwrap(),
+        kind: EventKind::Snatch {
+            peer_id: PeerId::from_bytes(&[1; 20]).unwrap(),
+            passkey: Some(String::from("passkey")),
+            ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            snatches: 1,
+        },
+    };
+    assert!(event.to_json().find("passkey").is_some() && event.to_json().find("ip").is_some());
+    let public = event.to_public_json();
+    assert!(public.find("passkey").is_none() && public.find("ip").is_none());
+    assert!(public.find("peer_id").is_some());
+}
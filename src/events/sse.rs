@@ -0,0 +1,150 @@
+use events::{EventSink, SwarmEvent};
+use http::constant_time_eq;
+
+use parking_lot::Mutex;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// Clients which fall this far behind are dropped.
+const WRITE_TIMEOUT: u64 = 10;
+/// Batches of events queued per client, clients with a full queue are dropped.
+const CLIENT_QUEUE: usize = 64;
+
+/// Queues of the connected clients, each fed batches of formatted events.
+type Clients = Arc<Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>>;
+
+/// Streams events to every client connected to its own listener as Server-Sent Events.
+/// Events carry passkeys and IPs, so clients have to give the admin token, either as
+/// `Authorization: Bearer <token>` or as the `token` query parameter.
+pub struct SseSink {
+    clients: Clients,
+}
+
+impl SseSink {
+    pub fn start(addr: &str, token: String) -> io::Result<SseSink> {
+        let listener = try!(TcpListener::bind(addr));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        let token = Arc::new(token);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("Failed to accept event stream client: {}", e);
+                        continue;
+                    }
+                };
+                // Every client gets a thread of its own, so slow ones only hold up themselves.
+                let clients = accepted.clone();
+                let token = token.clone();
+                thread::spawn(move || {
+                    match accept(stream, token.as_bytes()) {
+                        Ok(stream) => stream_events(stream, &clients),
+                        Err(e) => debug!("Failed to accept event stream client: {}", e),
+                    }
+                });
+            }
+        });
+        Ok(SseSink { clients: clients })
+    }
+}
+
+/// Reads the client's request and starts the stream if it has the admin token.
+fn accept(mut stream: TcpStream, token: &[u8]) -> io::Result<TcpStream> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(WRITE_TIMEOUT))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT))));
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        match try!(stream.read(&mut buf)) {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed early")),
+            n => req.extend_from_slice(&buf[..n]),
+        }
+        if req.len() > 8192 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+    }
+    if !is_authorized(&req, token) {
+        try!(stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\
+                                Connection: close\r\n\r\n"));
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad admin token"));
+    }
+    try!(stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                            Cache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"));
+    Ok(stream)
+}
+
+/// Looks for the token in the Authorization header, then in the request line's query.
+fn is_authorized(req: &[u8], token: &[u8]) -> bool {
+    let mut lines = req.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let request_line = lines.next().unwrap_or(b"");
+    let header = lines.filter_map(|line| {
+            let colon = match line.iter().position(|&b| b == b':') {
+                Some(colon) => colon,
+                None => return None,
+            };
+            if line[..colon].eq_ignore_ascii_case(b"authorization") {
+                line[colon + 1..].trim_ascii_start().strip_prefix(b"Bearer ")
+            } else {
+                None
+            }
+        })
+        .next();
+    if let Some(given) = header {
+        return constant_time_eq(given, token);
+    }
+    let target = request_line.split(|&b| b == b' ').nth(1).unwrap_or(b"");
+    let query = match target.iter().position(|&b| b == b'?') {
+        Some(i) => &target[i + 1..],
+        None => return false,
+    };
+    query.split(|&b| b == b'&')
+        .filter_map(|param| param.strip_prefix(b"token="))
+        .any(|given| constant_time_eq(given, token))
+}
+
+/// Writes out everything queued for the client until it falls behind or goes away.
+fn stream_events(mut stream: TcpStream, clients: &Clients) {
+    let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE);
+    clients.lock().push(tx);
+    for events in rx {
+        if stream.write_all(&events).is_err() {
+            return;
+        }
+    }
+}
+
+impl EventSink for SseSink {
+    fn handle(&mut self, events: &[SwarmEvent]) {
+        let mut buf = Vec::new();
+        for event in events {
+            let _ = write!(buf, "event: {}\ndata: {}\n\n", event.name(), event.to_json());
+        }
+        let buf = Arc::new(buf);
+        self.clients.lock().retain(|client| {
+            match client.try_send(buf.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping event stream client which fell behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+#[test]
+fn streams_need_the_admin_token() {
+    assert!(is_authorized(b"GET / HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n", b"secret"));
+    assert!(is_authorized(b"GET /?a=b&token=secret HTTP/1.1\r\n\r\n", b"secret"));
+    assert!(!is_authorized(b"GET / HTTP/1.1\r\nAuthorization: Bearer secre\r\n\r\n", b"secret"));
+    assert!(!is_authorized(b"GET /?token=other HTTP/1.1\r\n\r\n", b"secret"));
+    assert!(!is_authorized(b"GET / HTTP/1.1\r\n\r\n", b"secret"));
+}
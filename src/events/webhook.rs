@@ -0,0 +1,109 @@
+use events::{EventSink, SwarmEvent};
+
+use rustc_serialize::json::Json;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+const ATTEMPTS: usize = 3;
+const TIMEOUT: u64 = 10;
+
+/// POSTs batches of events to a URL as a JSON array, without passkeys and IPs. Only plain
+/// HTTP is supported, the receiving end is expected to be close by, e.g. the site backend.
+pub struct WebhookSink {
+    host: String,
+    path: String,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Option<WebhookSink> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return None,
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let host = if host.contains(':') && !host.ends_with(']') {
+            String::from(host)
+        } else {
+            format!("{}:80", host)
+        };
+        Some(WebhookSink {
+            host: host,
+            path: String::from(path),
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        let addr = match try!(self.host.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "host not found")),
+        };
+        let mut stream = try!(TcpStream::connect_timeout(&addr, Duration::from_secs(TIMEOUT)));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT))));
+        try!(stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT))));
+        try!(write!(stream,
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    self.path,
+                    self.host,
+                    body.len()));
+        try!(stream.write_all(body));
+
+        // Only the status line matters.
+        let mut buf = [0u8; 64];
+        let mut len = 0;
+        while len < buf.len() {
+            match try!(stream.read(&mut buf[len..])) {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let status = String::from_utf8_lossy(&buf[..len]);
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("unexpected response {:?}",
+                                           status.lines().next().unwrap_or(""))))
+            }
+        }
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn handle(&mut self, events: &[SwarmEvent]) {
+        let body = Json::Array(events.iter().map(|e| e.to_public_json()).collect()).to_string();
+        for attempt in 1..ATTEMPTS + 1 {
+            match self.post(body.as_bytes()) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!("Posting {} events to webhook {} failed (attempt {}): {}",
+                          events.len(),
+                          self.host,
+                          attempt,
+                          e);
+                    thread::sleep(Duration::from_secs(attempt as u64));
+                }
+            }
+        }
+        error!("Dropping {} events the webhook didn't accept!", events.len());
+    }
+}
+
+#[test]
+fn webhook_urls() {
+    let sink = WebhookSink::new("http://127.0.0.1:9000/hooks/tracker").unwrap();
+    assert!(sink.host == "127.0.0.1:9000" && sink.path == "/hooks/tracker");
+    let sink = WebhookSink::new("http://example.com").unwrap();
+    assert!(sink.host == "example.com:80" && sink.path == "/");
+    let sink = WebhookSink::new("http://[::1]/events").unwrap();
+    assert!(sink.host == "[::1]:80" && sink.path == "/events");
+    assert!(WebhookSink::new("https://example.com/").is_none());
+}
@@ -1,169 +1,449 @@
+mod tls;
+mod server;
+mod query;
+
 use response::TrackerResponse;
 use response::error::ErrorResponse;
-use response::success::SuccessResponse;
+use response::success::{bencode_announce, bencode_torrent, json_torrent, bencode_leaderboards,
+                        json_leaderboards, bencode_needs_seeders, json_needs_seeders,
+                        bencode_hit_and_runs, json_hit_and_runs, bencode_quarantine,
+                        json_quarantine};
 use tracker::Tracker;
-use tracker::announce::{Action, Announce};
+use tracker::announce::{Action, Announce, AnnounceResponse};
 use tracker::scrape::Scrape;
-use config::HttpConfig;
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use config::{Access, HttpConfig, ListenerConfig, PasskeyLocation, Protocol, ProxyPolicy};
 
-use hyper::server::{Request, Response, Handler};
-use hyper::Server;
-use hyper::uri::RequestUri::AbsolutePath;
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::collections::HashMap;
+pub use self::server::Request;
+use self::server::{Listener, Server};
+use self::tls::TlsServer;
+use self::query::Query;
+use mio;
+use mio_uds;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener};
+use std::os::unix::net::UnixListener;
 use std::sync::Arc;
-use url::{Url, UrlParser};
-use std::str::FromStr;
+use std::thread;
+use std::fs;
+use std::io;
+use std::str;
 use std::cmp;
 
 pub struct RequestHandler {
     pub tracker: Arc<Tracker>,
-    pub config: HttpConfig
+    pub config: ListenerConfig,
+    pub http: HttpConfig,
+}
+
+/// Buffers kept by every server worker and reused for each request, so that answering an
+/// announce doesn't have to allocate.
+#[derive(Default)]
+pub struct ResponseBuffer {
+    pub body: Vec<u8>,
+    announce: AnnounceResponse,
+}
+
+/// The announce parameters, still percent encoded.
+#[derive(Default)]
+struct AnnounceParams<'a> {
+    info_hash: Option<&'a str>,
+    peer_id: Option<&'a str>,
+    key: Option<&'a str>,
+    port: Option<&'a str>,
+    uploaded: Option<&'a str>,
+    downloaded: Option<&'a str>,
+    left: Option<&'a str>,
+    event: Option<&'a str>,
+    numwant: Option<&'a str>,
+    compact: Option<&'a str>,
+    ip: Option<&'a str>,
+    ipv4: Option<&'a str>,
+    ipv6: Option<&'a str>,
+}
+
+/// A bound listening socket, cloned into a non-blocking listener for every worker.
+enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
 }
 
-impl Handler for RequestHandler {
-    fn handle(&self, req: Request, res: Response) {
-        let resp = match req.uri {
-            AbsolutePath(ref path) => {
-                let base = Url::parse("http://localhost").unwrap();
-                let url = UrlParser::new().base_url(&base).parse(path).unwrap();
-                self.handle_url(&req, url)
+impl RequestHandler {
+    pub fn start(tracker: Arc<Tracker>,
+                 config: ListenerConfig,
+                 http: HttpConfig)
+                 -> io::Result<()> {
+        let tls = match config.protocol {
+            Protocol::Https => {
+                match (http.tls_cert.clone(), http.tls_key.clone()) {
+                    (Some(cert), Some(key)) => {
+                        let tls = try!(TlsServer::new(&cert, &key));
+                        tls.watch(cert, key, http.tls_reload_interval);
+                        Some(tls)
+                    }
+                    _ => {
+                        error!("HTTPS listener {} needs both tls_cert and tls_key!",
+                               config.address);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => None,
+        };
+        let listener = match config.unix_path() {
+            Some(path) => {
+                // A socket file left behind by a previous run would make binding fail.
+                let _ = fs::remove_file(path);
+                BoundListener::Unix(try!(UnixListener::bind(path)))
             }
-            _ => Err(ErrorResponse::BadAction),
+            None => BoundListener::Tcp(try!(TcpListener::bind(config.address.as_str()))),
         };
-        res.send(serialize_resp(resp).as_slice()).unwrap();
+        info!("{} interface listening on {}!",
+              if tls.is_some() { "HTTPS" } else { "HTTP" },
+              config.address);
+
+        let handler = Arc::new(RequestHandler {
+            tracker: tracker,
+            config: config,
+            http: http.clone(),
+        });
+        let max_connections = cmp::max(http.max_connections / http.workers, 1);
+        let mut workers = Vec::new();
+        for _ in 0..http.workers {
+            let listener = try!(listener.try_clone());
+            let tls = tls.clone();
+            let handler = handler.clone();
+            let keep_alive_timeout = http.keep_alive_timeout;
+            workers.push(thread::spawn(move || {
+                match Server::new(listener, tls, handler, max_connections, keep_alive_timeout) {
+                    Ok(mut server) => server.run(),
+                    Err(e) => error!("Failed to start an HTTP worker: {}", e),
+                }
+            }));
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+        Ok(())
     }
-}
 
-impl RequestHandler {
-    pub fn start(tracker: Arc<Tracker>, config: HttpConfig) {
-        let server = Server::http(config.listen_addr.as_str()).unwrap();
-        let handler = RequestHandler { tracker: tracker, config: config };
-        info!("HTTP interface listening on {}!", handler.config.listen_addr);
-        let _guard = server.handle(handler).unwrap();
+    /// Answers a request, leaving the response body in `resp.body`.
+    pub fn handle(&self, req: &Request, resp: &mut ResponseBuffer) {
+        resp.body.clear();
+        let (path, query) = match req.path.find('?') {
+            Some(i) => (&req.path[..i], &req.path[i + 1..]),
+            None => (req.path, ""),
+        };
+        if let Err(e) = self.handle_path(req, path, query, resp) {
+            resp.body.clear();
+            resp.body.extend(e.to_bencode());
+        }
     }
 
-    fn handle_url(&self, req: &Request, url: Url) -> Result<SuccessResponse, ErrorResponse> {
-        if url.path().is_none() {
-            return Err(ErrorResponse::BadAction);
+    fn is_private(&self) -> bool {
+        cfg!(feature = "private") && self.config.private
+    }
+
+    fn handle_path(&self,
+                   req: &Request,
+                   path: &str,
+                   query: &str,
+                   resp: &mut ResponseBuffer)
+                   -> Result<(), ErrorResponse> {
+        if let Some(hash) = path.strip_prefix("/torrent/") {
+            return self.handle_torrent(req, hash, query, resp);
         }
-        let path = url.path().unwrap();
-        let params = url.query_pairs();
-
-        if cfg!(feature = "private") {
-            if path.len() != 2 {
-                Err(ErrorResponse::BadRequest)
-            } else {
-                if self.tracker.private.validate_passkey(&path[0]) {
-                    Err(ErrorResponse::BadAuth)
-                } else {
-                    self.handle_req(req, &path[1], params, Some(path[0].clone()))
+        if path == "/leaderboards" {
+            return self.handle_leaderboards(req, query, resp);
+        }
+        if path == "/needs_seeders" {
+            return self.handle_needs_seeders(req, query, resp);
+        }
+        if path == "/hit_and_runs" {
+            return self.handle_hit_and_runs(req, query, resp);
+        }
+        if path == "/quarantine" {
+            return self.handle_quarantine(req, query, resp);
+        }
+        let mut segments = match path.strip_prefix('/') {
+            Some(path) => path.split('/'),
+            None => return Err(ErrorResponse::BadAction),
+        };
+
+        if self.is_private() {
+            let (passkey, action) = match (segments.next(), segments.next(), segments.next()) {
+                (Some(passkey), Some(action), None) if self.accepts_passkey_in(PasskeyLocation::Path) => {
+                    (Some(String::from(passkey)), action)
                 }
+                (Some(action), None, None) => (self.find_passkey(req, query), action),
+                _ => return Err(ErrorResponse::BadRequest),
+            };
+            match passkey {
+                Some(passkey) => {
+                    if passkey.is_empty() || !self.tracker.private.validate_passkey(&passkey) {
+                        Err(ErrorResponse::BadAuth)
+                    } else {
+                        self.handle_req(req, action, query, Some(passkey), resp)
+                    }
+                }
+                None => Err(ErrorResponse::BadAuth),
             }
         } else {
-            if path.len() != 1 {
-                Err(ErrorResponse::BadRequest)
-            } else {
-                self.handle_req(req, &path[0], params, None)
+            match (segments.next(), segments.next()) {
+                (Some(action), None) => self.handle_req(req, action, query, None, resp),
+                _ => Err(ErrorResponse::BadRequest),
             }
         }
     }
 
+    fn accepts_passkey_in(&self, location: PasskeyLocation) -> bool {
+        self.tracker.private.config.passkey_locations.contains(&location)
+    }
+
+    /// Looks for the passkey outside of the path, in the places the config allows.
+    fn find_passkey(&self, req: &Request, query: &str) -> Option<String> {
+        let mut buf = [0u8; 128];
+        let passkey = if self.accepts_passkey_in(PasskeyLocation::Query) {
+            Query::new(query)
+                .find(|&(key, _)| key == "passkey")
+                .and_then(|(_, val)| query::decode(val, &mut buf))
+        } else {
+            None
+        };
+        let passkey = passkey.or_else(|| if self.accepts_passkey_in(PasskeyLocation::Header) {
+            req.header("Authorization").and_then(|h| h.strip_prefix(b"Passkey "))
+        } else {
+            None
+        });
+        passkey.and_then(|p| str::from_utf8(p).ok()).map(String::from)
+    }
+
     fn handle_req(&self,
                   req: &Request,
-                  path: &String,
-                  params: Option<Vec<(String, String)>>,
-                  passkey: Option<String>)
-                  -> Result<SuccessResponse, ErrorResponse> {
-        match &path[..] {
-            "stats" => self.tracker.get_stats(),
+                  action: &str,
+                  query: &str,
+                  passkey: Option<String>,
+                  resp: &mut ResponseBuffer)
+                  -> Result<(), ErrorResponse> {
+        match action {
+            "stats" => {
+                let stats = try!(self.tracker.get_stats());
+                stats.write_http_resp(&mut resp.body);
+            }
             "announce" => {
-                let announce = try!(self.request_to_announce(req, params, passkey));
-                self.tracker.handle_announce(announce)
+                let announce = try!(self.request_to_announce(req, query, passkey));
+                try!(self.tracker.announce(&announce, &mut resp.announce));
+                if self.is_private() {
+                    resp.announce.warning = self.tracker.private.warning_message(&announce);
+                }
+                bencode_announce(&resp.announce, &mut resp.body);
             }
             "scrape" => {
-                let scrape = try!(self.request_to_scrape(params));
-                self.tracker.handle_scrape(scrape)
+                let scrape = try!(self.request_to_scrape(query));
+                let scrape = try!(self.tracker.handle_scrape(scrape));
+                scrape.write_http_resp(&mut resp.body);
             }
-            _ => Err(ErrorResponse::BadAction),
+            _ => return Err(ErrorResponse::BadAction),
         }
+        Ok(())
     }
 
-    fn request_to_scrape(&self,
-                         params: Option<Vec<(String, String)>>)
-                         -> Result<Scrape, ErrorResponse> {
-        if params.is_none() {
-            return Err(ErrorResponse::BadRequest);
+    /// Answers with a torrent's stats and history, as JSON unless `format=bencode` is given.
+    fn handle_torrent(&self,
+                      req: &Request,
+                      hash: &str,
+                      query: &str,
+                      resp: &mut ResponseBuffer)
+                      -> Result<(), ErrorResponse> {
+        try!(self.check_access(self.http.torrent_stats, req, query));
+        let hash = try!(InfoHash::from_hex(hash).ok_or(ErrorResponse::BadRequest));
+        let torrent = try!(self.tracker.get_torrent(&hash));
+        if wants_bencode(query) {
+            bencode_torrent(&torrent, &mut resp.body);
+        } else {
+            json_torrent(&torrent, &mut resp.body);
+        }
+        Ok(())
+    }
+
+    /// Answers with the torrent rankings of the last reap, in the same formats as torrents.
+    fn handle_leaderboards(&self,
+                           req: &Request,
+                           query: &str,
+                           resp: &mut ResponseBuffer)
+                           -> Result<(), ErrorResponse> {
+        try!(self.check_access(self.http.leaderboards, req, query));
+        let boards = self.tracker.get_leaderboards();
+        if wants_bencode(query) {
+            bencode_leaderboards(&boards, &mut resp.body);
+        } else {
+            json_leaderboards(&boards, &mut resp.body);
         }
-        let param_vec = params.unwrap();
+        Ok(())
+    }
 
-        let hashes = param_vec.into_iter()
-                              .map(|(_, hash)| hash)
-                              .collect();
-        Ok(Scrape::new(hashes))
+    /// Answers with the torrents short of seeders as of the last reap.
+    fn handle_needs_seeders(&self,
+                            req: &Request,
+                            query: &str,
+                            resp: &mut ResponseBuffer)
+                            -> Result<(), ErrorResponse> {
+        try!(self.check_access(self.http.needs_seeders, req, query));
+        let boards = self.tracker.leaderboards.read();
+        if wants_bencode(query) {
+            bencode_needs_seeders(&boards, &mut resp.body);
+        } else {
+            json_needs_seeders(&boards, &mut resp.body);
+        }
+        Ok(())
     }
 
-    fn request_to_announce(&self,
+    /// Lists hit-and-runs to admins, passkeys included.
+    fn handle_hit_and_runs(&self,
                            req: &Request,
-                           params: Option<Vec<(String, String)>>,
-                           passkey: Option<String>)
-                           -> Result<Announce, ErrorResponse> {
-        if params.is_none() {
-            return Err(ErrorResponse::BadRequest);
+                           query: &str,
+                           resp: &mut ResponseBuffer)
+                           -> Result<(), ErrorResponse> {
+        try!(self.check_access(Access::Admin, req, query));
+        let hnrs = self.tracker.private.hit_and_runs();
+        if wants_bencode(query) {
+            bencode_hit_and_runs(&hnrs, &mut resp.body);
+        } else {
+            json_hit_and_runs(&hnrs, &mut resp.body);
         }
-        let param_vec = params.unwrap();
-        if param_vec.len() > 10 {
-            return Err(ErrorResponse::BadRequest);
+        Ok(())
+    }
+
+    /// Lists quarantined announces to admins, crediting or dropping those of the passkey
+    /// given as `approve` or `reject` first.
+    fn handle_quarantine(&self,
+                         req: &Request,
+                         query: &str,
+                         resp: &mut ResponseBuffer)
+                         -> Result<(), ErrorResponse> {
+        try!(self.check_access(Access::Admin, req, query));
+        if !self.is_private() {
+            return Err(ErrorResponse::BadAction);
+        }
+        for (key, val) in Query::new(query) {
+            let approve = match key {
+                "approve" => true,
+                "reject" => false,
+                _ => continue,
+            };
+            let mut buf = [0u8; 128];
+            let passkey = try!(query::decode(val, &mut buf)
+                .and_then(|p| str::from_utf8(p).ok())
+                .ok_or(ErrorResponse::BadRequest));
+            self.tracker.private.review_quarantine(|d| {
+                if d.passkey.as_ref().is_some_and(|p| p == passkey) {
+                    Some(approve)
+                } else {
+                    None
+                }
+            });
+        }
+        let deltas = self.tracker.private.quarantined();
+        if wants_bencode(query) {
+            bencode_quarantine(&deltas, &mut resp.body);
+        } else {
+            json_quarantine(&deltas, &mut resp.body);
         }
+        Ok(())
+    }
 
-        let mut params = HashMap::new();
-        for (key, val) in param_vec {
-            params.insert(key, val);
+    fn check_access(&self, access: Access, req: &Request, query: &str) -> Result<(), ErrorResponse> {
+        match access {
+            Access::Off => Err(ErrorResponse::BadAction),
+            Access::Admin if !self.is_admin(req, query) => Err(ErrorResponse::BadAuth),
+            _ => Ok(()),
         }
+    }
 
-        let info_hash: String = try!(get_from_params(&params, String::from("info_hash")));
-        if cfg!(feature = "private") {
-            if !self.tracker.private.validate_torrent(&info_hash) {
-                return Err(ErrorResponse::BadAuth);
+    fn is_admin(&self, req: &Request, query: &str) -> bool {
+        let token = match self.http.admin_token {
+            Some(ref token) if !token.is_empty() => token.as_bytes(),
+            _ => return false,
+        };
+        let mut buf = [0u8; 128];
+        let given = match req.header("Authorization").and_then(|h| h.strip_prefix(b"Bearer ")) {
+            Some(given) => given,
+            None => {
+                match Query::new(query).find(|&(key, _)| key == "token") {
+                    Some((_, val)) => query::decode(val, &mut buf).unwrap_or(b""),
+                    None => return false,
+                }
             }
-        }
-        let pid: String = try!(get_from_params(&params, String::from("peer_id")));
-        if cfg!(feature = "private") {
-            if !self.tracker.private.validate_peer(&pid) {
-                return Err(ErrorResponse::BadPeer);
+        };
+        constant_time_eq(given, token)
+    }
+
+    fn request_to_scrape(&self, query: &str) -> Result<Scrape, ErrorResponse> {
+        let mut hashes = Vec::new();
+        for (key, val) in Query::new(query) {
+            if key == "info_hash" {
+                hashes.push(try!(decode_info_hash(val)));
             }
         }
-        if info_hash.len() > 40 || pid.len() > 30 {
+        if hashes.is_empty() {
             return Err(ErrorResponse::BadRequest);
         }
-        let ul = try!(get_from_params(&params, String::from("uploaded")));
-        let dl = try!(get_from_params(&params, String::from("downloaded")));
-        let left = try!(get_from_params(&params, String::from("left")));
+        Ok(Scrape::new(hashes))
+    }
 
-        // IP parsing according to BEP 0007 with additional proxy forwarding check
-        let port = try!(get_from_params(&params, String::from("port")));
-        let (ipv4, ipv6) = get_ips(&params, req, &port);
-        let action = match get_from_params::<String>(&params, String::from("event")) {
-            Ok(ev_str) => {
-                match &ev_str[..] {
-                    "started" => get_action(left),
-                    "stopped" => Action::Stopped,
-                    "completed" => Action::Completed,
-                    _ => get_action(left),
-                }
+    fn request_to_announce(&self,
+                           req: &Request,
+                           query: &str,
+                           passkey: Option<String>)
+                           -> Result<Announce, ErrorResponse> {
+        let params = try!(AnnounceParams::parse(query));
+
+        let info_hash = try!(decode_info_hash(try!(required(params.info_hash))));
+        if self.is_private() {
+            let hash = self.tracker.swarm_hash(&info_hash);
+            if let Some(e) = self.tracker.private.validate_torrent(&hash) {
+                return Err(e);
+            }
+        }
+        let mut buf = [0u8; 20];
+        let pid = try!(query::decode(try!(required(params.peer_id)), &mut buf)
+            .and_then(PeerId::from_bytes)
+            .ok_or(ErrorResponse::BadRequest));
+        if self.is_private() {
+            if let Some(e) = self.tracker.private.validate_peer(&pid) {
+                return Err(e);
             }
-            Err(_) => get_action(left),
+        }
+        // Keys longer than 40 bytes don't fit the buffer and are rejected.
+        let mut buf = [0u8; 40];
+        let key = match params.key {
+            Some(key) => {
+                let key = try!(query::decode(key, &mut buf).ok_or(ErrorResponse::BadRequest));
+                Some(String::from_utf8_lossy(key).into_owned())
+            }
+            None => None,
         };
+        let ul = try!(parse_required(params.uploaded));
+        let dl = try!(parse_required(params.downloaded));
+        let left = try!(parse_required(params.left));
+
+        // IP parsing according to BEP 0007 with additional proxy forwarding check
+        let port = try!(parse_required(params.port));
+        let (ipv4, ipv6) = get_ips(&params, req, port, &self.config.trusted_proxies);
+        let mut buf = [0u8; 16];
+        let event = params.event
+            .and_then(|e| query::decode(e, &mut buf))
+            .and_then(|e| str::from_utf8(e).ok());
+        let action = Action::from_event(event, left);
 
-        let numwant = cmp::min(get_from_params::<u8>(&params, String::from("numwant"))
-                                   .unwrap_or(25),
-                               25);
+        let numwant = cmp::min(params.numwant.and_then(query::parse::<u8>).unwrap_or(25), 25);
 
-        let compact = get_from_params::<u8>(&params, String::from("compact")).unwrap_or(1) != 0;
+        let compact = params.compact.and_then(query::parse::<u8>).unwrap_or(1) != 0;
         let announce = Announce {
             info_hash: info_hash,
             peer_id: pid,
             passkey: passkey,
+            key: key,
             ipv4: ipv4,
             ipv6: ipv6,
             ul: ul,
@@ -174,10 +454,10 @@ impl RequestHandler {
             compact: compact,
         };
 
-        if cfg!(feature = "private") {
-            match self.tracker.private.validate_announce(&announce) {
-                Some(e)  => return Err(e),
-                None => ()
+        if self.is_private() {
+            let hash = self.tracker.swarm_hash(&announce.info_hash);
+            if let Some(e) = self.tracker.private.validate_announce(&hash, &announce) {
+                return Err(e);
             }
         }
 
@@ -185,94 +465,134 @@ impl RequestHandler {
     }
 }
 
-fn get_ips(params: &HashMap<String, String>,
-           req: &Request,
-           port: &u16)
-           -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
-    let port = *port;
-    let default_ip = match req.headers.get_raw("X-Forwarded-For") {
-        Some(bytes) => {
-            match String::from_utf8(bytes[0].clone()) {
-                Ok(ip_str) => {
-                    match ip_str.parse::<IpAddr>() {
-                        Ok(ip) => SocketAddr::new(ip, port),
-                        Err(_) => req.remote_addr,
+impl<'a> AnnounceParams<'a> {
+    fn parse(query: &'a str) -> Result<AnnounceParams<'a>, ErrorResponse> {
+        let mut params = AnnounceParams::default();
+        for (key, val) in Query::new(query) {
+            let param = match key {
+                "info_hash" => {
+                    // Only one torrent can be announced at a time.
+                    if params.info_hash.is_some() {
+                        return Err(ErrorResponse::BadRequest);
                     }
+                    &mut params.info_hash
                 }
-                Err(_) => req.remote_addr,
+                "peer_id" => &mut params.peer_id,
+                "key" => &mut params.key,
+                "port" => &mut params.port,
+                "uploaded" => &mut params.uploaded,
+                "downloaded" => &mut params.downloaded,
+                "left" => &mut params.left,
+                "event" => &mut params.event,
+                "numwant" => &mut params.numwant,
+                "compact" => &mut params.compact,
+                "ip" => &mut params.ip,
+                "ipv4" => &mut params.ipv4,
+                "ipv6" => &mut params.ipv6,
+                _ => continue,
+            };
+            *param = Some(val);
+        }
+        Ok(params)
+    }
+}
+
+fn get_ips(params: &AnnounceParams,
+           req: &Request,
+           port: u16,
+           proxies: &ProxyPolicy)
+           -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
+    let forwarded = if proxies.trusts(&req.remote_addr.ip()) {
+        req.header("X-Forwarded-For")
+    } else {
+        None
+    };
+    // Peers listen on the announced port, not on the one they connected from.
+    let remote_addr = SocketAddr::new(req.remote_addr.ip(), port);
+    let default_ip = match forwarded.map(str::from_utf8) {
+        // The left-most entry is the client, the rest are the proxies in between.
+        Some(Ok(ip_str)) => {
+            match ip_str.split(',').next().unwrap_or("").trim().parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, port),
+                Err(_) => remote_addr,
             }
         }
-        None => req.remote_addr,
+        _ => remote_addr,
     };
-    let ip = match get_from_params(&params, String::from("ip")) {
-        Ok(ip) => SocketAddr::new(ip, port),
-        Err(_) => default_ip,
+    let ip = match params.ip.and_then(query::parse::<IpAddr>) {
+        Some(ip) => SocketAddr::new(ip, port),
+        None => default_ip,
     };
 
     match ip {
         SocketAddr::V4(v4) => {
-            let v6 = match get_socket(&params, String::from("ipv6"), port) {
-                Some(sock) => {
-                    match sock {
-                        SocketAddr::V6(v6) => Some(v6),
-                        _ => None,
-                    }
-                }
-                None => None,
+            let v6 = match get_socket(params.ipv6, port) {
+                Some(SocketAddr::V6(v6)) => Some(v6),
+                _ => None,
             };
             (Some(v4), v6)
         }
         SocketAddr::V6(v6) => {
-            let v4 = match get_socket(&params, String::from("ipv4"), port) {
-                Some(sock) => {
-                    match sock {
-                        SocketAddr::V4(v4) => Some(v4),
-                        _ => None,
-                    }
-                }
-                None => None,
+            let v4 = match get_socket(params.ipv4, port) {
+                Some(SocketAddr::V4(v4)) => Some(v4),
+                _ => None,
             };
             (v4, Some(v6))
         }
     }
 }
 
-fn get_from_params<T: FromStr>(map: &HashMap<String, String>,
-                               key: String)
-                               -> Result<T, ErrorResponse> {
-    match map.get(&key) {
-        Some(res) => {
-            match res.parse::<T>() {
-                Ok(val) => Ok(val),
-                Err(_) => Err(ErrorResponse::BadRequest),
-            }
-        }
-        None => Err(ErrorResponse::BadRequest),
-    }
+/// Info hashes are raw bytes, so they are decoded without going through a string.
+fn decode_info_hash(raw: &str) -> Result<InfoHash, ErrorResponse> {
+    let mut buf = [0u8; 32];
+    query::decode(raw, &mut buf).and_then(InfoHash::from_bytes).ok_or(ErrorResponse::BadRequest)
 }
 
-fn get_socket(params: &HashMap<String, String>, key: String, port: u16) -> Option<SocketAddr> {
-    let ip = get_from_params(params, key.clone());
-    let socket = get_from_params(params, key.clone());
-    match (ip, socket) {
-        (Err(_), Err(_)) => None,
-        (Ok(ip), Err(_)) => Some(SocketAddr::new(ip, port)),
-        (Err(_), Ok(sock)) => Some(sock),
-        _ => None,
+fn wants_bencode(query: &str) -> bool {
+    Query::new(query).any(|(key, val)| key == "format" && val == "bencode")
+}
+
+/// Compares without bailing out at the first difference, so that tokens can't be guessed a
+/// byte at a time from response times.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
-fn get_action(left: u64) -> Action {
-    if left == 0 {
-        Action::Seeding
-    } else {
-        Action::Leeching
+fn required(param: Option<&str>) -> Result<&str, ErrorResponse> {
+    param.ok_or(ErrorResponse::BadRequest)
+}
+
+fn parse_required<T: str::FromStr>(param: Option<&str>) -> Result<T, ErrorResponse> {
+    param.and_then(query::parse).ok_or(ErrorResponse::BadRequest)
+}
+
+/// BEP 7 allows the address to be given either with or without a port.
+fn get_socket(param: Option<&str>, port: u16) -> Option<SocketAddr> {
+    let param = match param {
+        Some(param) => param,
+        None => return None,
+    };
+    match query::parse::<IpAddr>(param) {
+        Some(ip) => Some(SocketAddr::new(ip, port)),
+        None => query::parse(param),
     }
 }
 
-fn serialize_resp(result: Result<SuccessResponse, ErrorResponse>) -> Vec<u8> {
-    match result {
-        Ok(resp) => resp.http_resp(),
-        Err(err) => err.to_bencode(),
+impl BoundListener {
+    fn try_clone(&self) -> io::Result<Listener> {
+        match *self {
+            BoundListener::Tcp(ref l) => {
+                let l = try!(l.try_clone());
+                try!(l.set_nonblocking(true));
+                mio::net::TcpListener::from_std(l).map(Listener::Tcp)
+            }
+            BoundListener::Unix(ref l) => {
+                let l = try!(l.try_clone());
+                mio_uds::UnixListener::from_listener(l).map(Listener::Unix)
+            }
+        }
     }
 }
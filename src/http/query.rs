@@ -0,0 +1,93 @@
+use std::str::{self, FromStr};
+
+/// Iterates over the `key=value` pairs of a query string. Values are handed out still percent
+/// encoded, they can be decoded on the stack with `decode` once it's known which ones matter.
+pub struct Query<'a> {
+    pairs: str::Split<'a, char>,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(query: &'a str) -> Query<'a> {
+        Query { pairs: query.split('&') }
+    }
+}
+
+impl<'a> Iterator for Query<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        loop {
+            let pair = match self.pairs.next() {
+                Some(pair) => pair,
+                None => return None,
+            };
+            if pair.is_empty() {
+                continue;
+            }
+            return match pair.find('=') {
+                Some(i) => Some((&pair[..i], &pair[i + 1..])),
+                None => Some((pair, "")),
+            };
+        }
+    }
+}
+
+/// Percent decodes `raw` into `buf`, returning the decoded bytes. Fails on malformed escapes
+/// or if `buf` is too small to hold the result.
+pub fn decode<'b>(raw: &str, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+    let raw = raw.as_bytes();
+    let mut len = 0;
+    let mut i = 0;
+    while i < raw.len() {
+        if len == buf.len() {
+            return None;
+        }
+        buf[len] = match raw[i] {
+            b'%' => {
+                if i + 2 >= raw.len() {
+                    return None;
+                }
+                match (hex_value(raw[i + 1]), hex_value(raw[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        i += 2;
+                        (hi << 4) | lo
+                    }
+                    _ => return None,
+                }
+            }
+            b'+' => b' ',
+            b => b,
+        };
+        len += 1;
+        i += 1;
+    }
+    Some(&buf[..len])
+}
+
+/// Decodes and parses a value, e.g. a number or an address.
+pub fn parse<T: FromStr>(raw: &str) -> Option<T> {
+    let mut buf = [0u8; 64];
+    decode(raw, &mut buf)
+        .and_then(|bytes| str::from_utf8(bytes).ok())
+        .and_then(|s| s.parse().ok())
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[test]
+fn query_decoding() {
+    let pairs: Vec<_> = Query::new("info_hash=%01%ab+&&port=6881&compact").collect();
+    assert!(pairs == vec![("info_hash", "%01%ab+"), ("port", "6881"), ("compact", "")]);
+
+    let mut buf = [0u8; 20];
+    assert!(decode("%01%ab+", &mut buf) == Some(&[0x01, 0xab, b' '][..]));
+    assert!(decode("%0", &mut buf).is_none());
+    assert!(decode("%zz", &mut buf).is_none());
+    assert!(decode("%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00%00",
+                   &mut buf)
+        .is_none());
+    assert!(parse::<u16>("6881") == Some(6881));
+    assert!(parse::<u16>("-1").is_none());
+}
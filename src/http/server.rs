@@ -0,0 +1,451 @@
+use http::{RequestHandler, ResponseBuffer};
+use http::tls::TlsServer;
+
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+use mio_uds::{UnixListener, UnixStream};
+use httparse;
+use rustls::{ServerSession, Session};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+/// Tracker requests are small GETs, anything bigger than this is not worth parsing.
+const MAX_REQUEST_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A parsed request, borrowing from the connection's read buffer.
+pub struct Request<'a> {
+    pub path: &'a str,
+    pub remote_addr: SocketAddr,
+    headers: &'a [httparse::Header<'a>],
+}
+
+struct Connection {
+    socket: Socket,
+    tls: Option<ServerSession>,
+    remote_addr: SocketAddr,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    keep_alive: bool,
+    last_active: Instant,
+}
+
+/// Single threaded, event driven HTTP/1.1 server. Each worker thread runs one of these over a
+/// shared listening socket, requests are handed to the synchronous `RequestHandler` as soon
+/// as they're complete.
+pub struct Server {
+    poll: Poll,
+    listener: Listener,
+    tls: Option<TlsServer>,
+    handler: Arc<RequestHandler>,
+    buf: ResponseBuffer,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    max_connections: usize,
+    keep_alive_timeout: Duration,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(path: &'a str,
+               remote_addr: SocketAddr,
+               headers: &'a [httparse::Header<'a>])
+               -> Request<'a> {
+        Request {
+            path: path,
+            remote_addr: remote_addr,
+            headers: headers,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+}
+
+impl Server {
+    pub fn new(listener: Listener,
+               tls: Option<TlsServer>,
+               handler: Arc<RequestHandler>,
+               max_connections: usize,
+               keep_alive_timeout: Duration)
+               -> io::Result<Server> {
+        let poll = try!(Poll::new());
+        match listener {
+            Listener::Tcp(ref l) => {
+                try!(poll.register(l, LISTENER, Ready::readable(), PollOpt::edge()))
+            }
+            Listener::Unix(ref l) => {
+                try!(poll.register(l, LISTENER, Ready::readable(), PollOpt::edge()))
+            }
+        }
+        Ok(Server {
+            poll: poll,
+            listener: listener,
+            tls: tls,
+            handler: handler,
+            buf: ResponseBuffer::default(),
+            connections: HashMap::new(),
+            next_token: 1,
+            max_connections: max_connections,
+            keep_alive_timeout: keep_alive_timeout,
+        })
+    }
+
+    pub fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(Duration::from_secs(1))) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed to poll for events: {}", e);
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    token => self.ready(token, event.readiness()),
+                }
+            }
+            if last_sweep.elapsed() >= Duration::from_secs(1) {
+                self.close_idle();
+                last_sweep = Instant::now();
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let accepted = match self.listener {
+                Listener::Tcp(ref l) => {
+                    match l.accept() {
+                        Ok((s, addr)) => Ok(Some((Socket::Tcp(s), addr))),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                }
+                Listener::Unix(ref l) => {
+                    l.accept().map(|s| s.map(|(s, _)| (Socket::Unix(s), unspecified_addr())))
+                }
+            };
+            let (socket, addr) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            // Past the limit connections are closed right away rather than left in the backlog.
+            if self.connections.len() >= self.max_connections {
+                debug!("Connection limit reached, dropping connection from {}", addr);
+                continue;
+            }
+            let token = self.next_token();
+            if let Err(e) = socket.register(&self.poll, token) {
+                warn!("Failed to register connection: {}", e);
+                continue;
+            }
+            let tls = self.tls.as_ref().map(|t| t.new_session());
+            self.connections.insert(token, Connection::new(socket, tls, addr));
+        }
+    }
+
+    fn ready(&mut self, token: Token, readiness: Ready) {
+        let open = match self.connections.get_mut(&token) {
+            Some(conn) => {
+                conn.last_active = Instant::now();
+                let mut open = true;
+                if readiness.is_readable() {
+                    open = conn.read();
+                    conn.process(&self.handler, &mut self.buf);
+                    // Whatever comes after is left unread, the connection only waits to
+                    // flush its responses.
+                    if !open {
+                        conn.keep_alive = false;
+                    }
+                }
+                conn.flush() && (open || conn.has_pending_writes())
+            }
+            None => return,
+        };
+        if !open {
+            self.close(token);
+        }
+    }
+
+    fn close_idle(&mut self) {
+        let timeout = self.keep_alive_timeout;
+        let idle: Vec<_> = self.connections
+            .iter()
+            .filter(|&(_, conn)| conn.last_active.elapsed() > timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = conn.socket.deregister(&self.poll);
+        }
+    }
+
+    fn next_token(&mut self) -> Token {
+        loop {
+            self.next_token = self.next_token.wrapping_add(1);
+            let token = Token(self.next_token);
+            if token != LISTENER && !self.connections.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn new(socket: Socket, tls: Option<ServerSession>, remote_addr: SocketAddr) -> Connection {
+        Connection {
+            socket: socket,
+            tls: tls,
+            remote_addr: remote_addr,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            keep_alive: true,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Reads everything available off the socket, returning false once the peer is gone or has
+    /// sent more than a request can take without completing one.
+    fn read(&mut self) -> bool {
+        if !self.keep_alive {
+            return false;
+        }
+        match self.tls {
+            Some(ref mut session) => {
+                loop {
+                    match session.read_tls(&mut self.socket) {
+                        Ok(0) => return false,
+                        Ok(_) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => return false,
+                    }
+                    if let Err(e) = session.process_new_packets() {
+                        debug!("TLS error from {}: {:?}", self.remote_addr, e);
+                        // Try to let the client know what went wrong before hanging up.
+                        let _ = session.write_tls(&mut self.socket);
+                        return false;
+                    }
+                }
+                let mut buf = [0u8; 4096];
+                loop {
+                    match session.read(&mut buf) {
+                        Ok(0) => return true,
+                        Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                        Err(_) => return false,
+                    }
+                    if self.read_buf.len() > MAX_REQUEST_SIZE {
+                        return false;
+                    }
+                }
+            }
+            None => {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match self.socket.read(&mut buf) {
+                        Ok(0) => return false,
+                        // A short read means the socket has been drained, which saves the
+                        // extra read that would only return WouldBlock.
+                        Ok(n) if n < buf.len() => {
+                            self.read_buf.extend_from_slice(&buf[..n]);
+                            return self.read_buf.len() <= MAX_REQUEST_SIZE;
+                        }
+                        Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => return false,
+                    }
+                    if self.read_buf.len() > MAX_REQUEST_SIZE {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles all complete requests in the read buffer, pipelined ones included.
+    fn process(&mut self, handler: &RequestHandler, buf: &mut ResponseBuffer) {
+        while self.keep_alive && !self.read_buf.is_empty() {
+            let (len, keep_alive) = {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut req = httparse::Request::new(&mut headers);
+                match req.parse(&self.read_buf) {
+                    Ok(httparse::Status::Complete(len)) => {
+                        let keep_alive = wants_keep_alive(&req);
+                        let request = Request {
+                            path: req.path.unwrap_or("/"),
+                            remote_addr: self.remote_addr,
+                            headers: req.headers,
+                        };
+                        handler.handle(&request, buf);
+                        (len, keep_alive)
+                    }
+                    Ok(httparse::Status::Partial) if self.read_buf.len() <= MAX_REQUEST_SIZE => {
+                        return;
+                    }
+                    _ => {
+                        self.keep_alive = false;
+                        self.respond("400 Bad Request", b"");
+                        return;
+                    }
+                }
+            };
+            self.read_buf.drain(..len);
+            self.keep_alive = keep_alive;
+            self.respond("200 OK", &buf.body);
+        }
+    }
+
+    fn respond(&mut self, status: &str, body: &[u8]) {
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+        match self.tls {
+            Some(ref mut session) => {
+                let _ = write_head(session, status, body.len(), connection);
+                let _ = session.write_all(body);
+            }
+            None => {
+                let _ = write_head(&mut self.write_buf, status, body.len(), connection);
+                self.write_buf.extend_from_slice(body);
+            }
+        }
+    }
+
+    /// Writes out as much as the socket takes. Returns false if the connection should be
+    /// closed, either on error or because everything for a non keep-alive connection is out.
+    fn flush(&mut self) -> bool {
+        match self.tls {
+            Some(ref mut session) => {
+                while session.wants_write() {
+                    match session.write_tls(&mut self.socket) {
+                        Ok(_) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => return false,
+                    }
+                }
+            }
+            None => {
+                while !self.write_buf.is_empty() {
+                    match self.socket.write(&self.write_buf) {
+                        Ok(0) => return false,
+                        Ok(n) => {
+                            self.write_buf.drain(..n);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => return false,
+                    }
+                }
+            }
+        }
+        self.keep_alive
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        match self.tls {
+            Some(ref session) => session.wants_write(),
+            None => !self.write_buf.is_empty(),
+        }
+    }
+}
+
+impl Socket {
+    fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        let interest = Ready::readable() | Ready::writable();
+        match *self {
+            Socket::Tcp(ref s) => poll.register(s, token, interest, PollOpt::edge()),
+            Socket::Unix(ref s) => poll.register(s, token, interest, PollOpt::edge()),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref s) => poll.deregister(s),
+            Socket::Unix(ref s) => poll.deregister(s),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut s) => s.read(buf),
+            Socket::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut s) => s.write(buf),
+            Socket::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref mut s) => s.flush(),
+            Socket::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn write_head<W: Write>(w: &mut W, status: &str, len: usize, connection: &str) -> io::Result<()> {
+    write!(w,
+           "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: \
+            {}\r\n\r\n",
+           status,
+           len,
+           connection)
+}
+
+/// HTTP/1.1 connections are persistent unless asked otherwise, HTTP/1.0 ones only on request.
+fn wants_keep_alive(req: &httparse::Request) -> bool {
+    let connection = req.headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("connection"))
+        .map(|h| h.value);
+    match (req.version, connection) {
+        (_, Some(v)) if v.eq_ignore_ascii_case(b"close") => false,
+        (_, Some(v)) if v.eq_ignore_ascii_case(b"keep-alive") => true,
+        (Some(1), _) => true,
+        _ => false,
+    }
+}
+
+/// Unix sockets have no peer address, the client address has to come from the proxy through
+/// X-Forwarded-For.
+pub fn unspecified_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+}
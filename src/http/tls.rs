@@ -0,0 +1,91 @@
+use rustls::{NoClientAuth, ServerConfig, ServerSession};
+use rustls::internal::pemfile;
+use parking_lot::RwLock;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// TLS configuration backed by rustls. The certificate is watched on disk and swapped out
+/// when it changes, new connections pick up the new certificate while established ones finish
+/// on the old one.
+#[derive(Clone)]
+pub struct TlsServer {
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn new(cert: &str, key: &str) -> io::Result<TlsServer> {
+        let config = try!(load_config(cert, key));
+        Ok(TlsServer { config: Arc::new(RwLock::new(Arc::new(config))) })
+    }
+
+    pub fn new_session(&self) -> ServerSession {
+        ServerSession::new(&self.config.read())
+    }
+
+    pub fn watch(&self, cert: String, key: String, interval: Duration) {
+        let config = self.config.clone();
+        thread::spawn(move || {
+            let mut last_modified = modified(&cert, &key);
+            loop {
+                thread::sleep(interval);
+                let current = modified(&cert, &key);
+                if current == last_modified {
+                    continue;
+                }
+                // On failure the old certificate stays in use and the load is retried on the
+                // next tick, e.g. when the key is replaced shortly after the certificate.
+                match load_config(&cert, &key) {
+                    Ok(c) => {
+                        *config.write() = Arc::new(c);
+                        last_modified = current;
+                        info!("Reloaded TLS certificate {}!", cert);
+                    }
+                    Err(e) => error!("Failed to reload TLS certificate {}: {}", cert, e),
+                }
+            }
+        });
+    }
+}
+
+fn load_config(cert: &str, key: &str) -> io::Result<ServerConfig> {
+    let cert_pem = try!(read_file(cert));
+    let certs = try!(pemfile::certs(&mut &cert_pem[..])
+        .map_err(|_| invalid_data(format!("{} is not a PEM certificate", cert))));
+
+    // Keys may either be in PKCS8 or in the traditional RSA format.
+    let key_pem = try!(read_file(key));
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key_pem[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &key_pem[..]).unwrap_or_default();
+    }
+    if keys.is_empty() {
+        return Err(invalid_data(format!("{} does not contain a private key", key)));
+    }
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    try!(config.set_single_cert(certs, keys.remove(0))
+        .map_err(|e| invalid_data(format!("{:?}", e))));
+    Ok(config)
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)));
+    Ok(buf)
+}
+
+fn modified(cert: &str, key: &str) -> Option<(SystemTime, SystemTime)> {
+    let cert_time = fs::metadata(cert).and_then(|m| m.modified()).ok();
+    let key_time = fs::metadata(key).and_then(|m| m.modified()).ok();
+    match (cert_time, key_time) {
+        (Some(c), Some(k)) => Some((c, k)),
+        _ => None,
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
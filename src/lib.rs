@@ -0,0 +1,30 @@
+// The code base predates the `?` operator and field init shorthand, so keep the idioms used
+// throughout from being flagged.
+#![allow(deprecated)]
+#![allow(clippy::redundant_field_names, clippy::option_map_or_none, clippy::question_mark,
+         clippy::manual_is_multiple_of)]
+
+extern crate mio;
+extern crate mio_uds;
+extern crate httparse;
+extern crate time;
+extern crate toml;
+extern crate parking_lot;
+extern crate ws;
+extern crate rustc_serialize;
+extern crate rustls;
+
+#[macro_use]
+extern crate bip_bencode;
+
+#[macro_use]
+extern crate log;
+
+pub mod tracker;
+pub mod http;
+pub mod response;
+pub mod private;
+pub mod config;
+pub mod websocket;
+pub mod udp;
+pub mod events;
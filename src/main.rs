@@ -1,30 +1,25 @@
-extern crate hyper;
-extern crate url;
-extern crate time;
+// The code base predates the `?` operator and field init shorthand, so keep the idioms used
+// throughout from being flagged.
+#![allow(deprecated)]
+#![allow(clippy::redundant_field_names, clippy::option_map_or_none, clippy::question_mark,
+         clippy::manual_is_multiple_of)]
+
+extern crate sanka;
 extern crate env_logger;
 extern crate getopts;
 extern crate toml;
-extern crate parking_lot;
-
-#[macro_use]
-extern crate bip_bencode;
-
 #[macro_use]
 extern crate log;
 
-mod tracker;
-mod http;
-mod response;
-mod private;
-mod config;
-
-use tracker::Tracker;
+use sanka::{events, http, udp, websocket};
+use sanka::tracker::Tracker;
+use sanka::config::{MainConfig, Protocol};
 use std::sync::Arc;
-use config::MainConfig;
 use getopts::Options;
 use std::env;
 use std::io::Read;
 use std::fs::File;
+use std::thread;
 
 fn main() {
     env_logger::init().unwrap();
@@ -36,7 +31,7 @@ fn main() {
     opts.optopt("c", "config", "path to config file", "/path/to/config.toml");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f.to_string()) }
     };
 
     if matches.opt_present("h") {
@@ -71,6 +66,37 @@ fn print_usage(program: &str, opts: Options) {
 fn run_tracker(config: MainConfig) {
     let tracker = Tracker::new(config.tracker.clone(), config.private.clone());
     let tracker_arc = Arc::new(tracker);
+    events::start(&tracker_arc.events, &config.events, config.http.admin_token.as_ref());
     Tracker::start_updaters(tracker_arc.clone());
-    http::RequestHandler::start(tracker_arc.clone(), config.http.clone());
+    if let Some(ws_config) = config.websocket.clone() {
+        let tracker_ws = tracker_arc.clone();
+        thread::spawn(move || {
+            if let Err(e) = websocket::WebSocketHandler::start(tracker_ws, ws_config) {
+                error!("Failed to start the WebSocket interface: {}", e);
+            }
+        });
+    }
+
+    let listeners: Vec<_> = config.http
+        .listeners
+        .iter()
+        .cloned()
+        .map(|listener| {
+            let tracker_listener = tracker_arc.clone();
+            let http_config = config.http.clone();
+            thread::spawn(move || {
+                let address = listener.address.clone();
+                let res = match listener.protocol {
+                    Protocol::Udp => udp::UdpHandler::start(tracker_listener, listener),
+                    _ => http::RequestHandler::start(tracker_listener, listener, http_config),
+                };
+                if let Err(e) = res {
+                    error!("Failed to start listener {}: {}", address, e);
+                }
+            })
+        })
+        .collect();
+    for listener in listeners {
+        let _ = listener.join();
+    }
 }
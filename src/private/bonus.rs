@@ -0,0 +1,61 @@
+use toml::Value;
+
+const GIB: f64 = 1073741824.0;
+
+/// Bonus points earned for seeding, per hour:
+/// `base * size_in_gib ^ size_exponent / seeders ^ seeder_exponent`.
+/// Bigger torrents earn more, as do torrents with fewer seeders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BonusFormula {
+    pub base: f64,
+    pub size_exponent: f64,
+    pub seeder_exponent: f64,
+}
+
+impl Default for BonusFormula {
+    fn default() -> BonusFormula {
+        BonusFormula {
+            base: 0.0,
+            size_exponent: 0.5,
+            seeder_exponent: 0.5,
+        }
+    }
+}
+
+impl BonusFormula {
+    pub fn from_toml(toml: &Value) -> BonusFormula {
+        let default = BonusFormula::default();
+        BonusFormula {
+            base: toml.lookup("base")
+                .map_or(None, |v| v.as_float())
+                .unwrap_or(default.base),
+            size_exponent: toml.lookup("size_exponent")
+                .map_or(None, |v| v.as_float())
+                .unwrap_or(default.size_exponent),
+            seeder_exponent: toml.lookup("seeder_exponent")
+                .map_or(None, |v| v.as_float())
+                .unwrap_or(default.seeder_exponent),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.base > 0.0
+    }
+
+    /// Points for seeding a torrent of `size` bytes with `seeders` seeders for `seed_time`
+    /// seconds.
+    pub fn points(&self, size: u64, seeders: u64, seed_time: u64) -> f64 {
+        let size = (size as f64 / GIB).powf(self.size_exponent);
+        let seeders = (seeders.max(1) as f64).powf(self.seeder_exponent);
+        self.base * size / seeders * seed_time as f64 / 3600.0
+    }
+}
+
+#[test]
+fn bonus_points() {
+    let formula = BonusFormula { base: 2.0, ..Default::default() };
+    assert!(formula.points(4 * GIB as u64, 1, 3600) == 4.0);
+    assert!(formula.points(4 * GIB as u64, 4, 1800) == 1.0);
+    assert!(formula.points(0, 1, 3600) == 0.0);
+    assert!(!BonusFormula::default().is_enabled());
+}
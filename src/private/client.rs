@@ -0,0 +1,314 @@
+use tracker::peer_id::PeerId;
+
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Names of the Azureus style client codes, as in `-qB4250-`.
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "uTorrent Mac"),
+    ("UT", "uTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Names of the Shadow style client codes, as in `T03I--`.
+const SHADOW_CLIENTS: &[(&str, &str)] = &[
+    ("A", "ABC"),
+    ("O", "Osprey Permaseed"),
+    ("Q", "BTQueue"),
+    ("R", "Tribler"),
+    ("S", "Shadow's client"),
+    ("T", "BitTornado"),
+    ("U", "UPnP NAT Bit Torrent"),
+];
+
+/// A client and its version as encoded in a peer id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Client {
+    /// The two letter Azureus style code, or the single letter Shadow style one.
+    pub code: String,
+    pub version: Version,
+}
+
+/// Version numbers, missing trailing parts count as zero.
+#[derive(Clone, Copy, Debug)]
+pub struct Version {
+    parts: [u32; 5],
+    len: usize,
+}
+
+/// Decides which clients may announce. Rules are checked in order and the first one
+/// matching decides, clients no rule matches are only allowed if there are no allow rules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientRule {
+    allow: bool,
+    client: ClientMatch,
+    version: Option<(Ordering, bool, Version)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ClientMatch {
+    Any,
+    /// Raw peer id prefix, e.g. `-XL`.
+    Prefix(String),
+    /// Client code or name, ignoring case.
+    Client(String),
+}
+
+impl Client {
+    /// Parses Azureus (`-qB4250-`) and Shadow (`T03I--`) style peer ids.
+    pub fn parse(id: &PeerId) -> Option<Client> {
+        let id = id.as_bytes();
+        if id[0] == b'-' && id[7] == b'-' && id[1..3].iter().all(u8::is_ascii_alphanumeric) {
+            let mut version = Version::new();
+            for &c in &id[3..7] {
+                match shadow_digit(c) {
+                    Some(digit) => version.push(digit),
+                    None => return None,
+                }
+            }
+            return Some(Client {
+                code: String::from_utf8_lossy(&id[1..3]).into_owned(),
+                version: version,
+            });
+        }
+        if SHADOW_CLIENTS.iter().any(|&(code, _)| code.as_bytes()[0] == id[0]) {
+            // Up to five version digits, padded with dashes.
+            let mut version = Version::new();
+            for &c in id[1..6].iter().take_while(|&&c| c != b'-') {
+                match shadow_digit(c) {
+                    Some(digit) => version.push(digit),
+                    None => return None,
+                }
+            }
+            if version.len > 0 && id[1..7].contains(&b'-') {
+                return Some(Client {
+                    code: String::from_utf8_lossy(&id[..1]).into_owned(),
+                    version: version,
+                });
+            }
+        }
+        None
+    }
+
+    pub fn name(&self) -> &str {
+        let clients = if self.code.len() == 2 {
+            AZUREUS_CLIENTS
+        } else {
+            SHADOW_CLIENTS
+        };
+        clients.iter()
+            .find(|&&(code, _)| code == self.code)
+            .map_or(&self.code[..], |&(_, name)| name)
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name(), self.version)
+    }
+}
+
+/// Shadow style version digits, which Azureus style ids mostly use as plain digits.
+fn shadow_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+impl Version {
+    fn new() -> Version {
+        Version {
+            parts: [0; 5],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, part: u32) {
+        if self.len < self.parts.len() {
+            self.parts[self.len] = part;
+            self.len += 1;
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Version> {
+        let mut version = Version::new();
+        for part in s.split('.') {
+            if version.len == version.parts.len() {
+                return None;
+            }
+            match part.parse() {
+                Ok(part) => version.push(part),
+                Err(_) => return None,
+            }
+        }
+        Some(version)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.parts == other.parts
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.parts.cmp(&other.parts)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, part) in self.parts[..self.len].iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, "."));
+            }
+            try!(write!(f, "{}", part));
+        }
+        Ok(())
+    }
+}
+
+impl ClientRule {
+    /// Parses rules like `allow qBittorrent >= 4.3`, `deny -XL` or `deny any`.
+    pub fn parse(rule: &str) -> Option<ClientRule> {
+        let words: Vec<_> = rule.split_whitespace().collect();
+        let (allow, rest) = match words.split_first() {
+            Some((&"allow", rest)) => (true, rest),
+            Some((&"deny", rest)) => (false, rest),
+            _ => return None,
+        };
+        // Names can have spaces in them, the version comparison is always the last two words.
+        let (client, version) = match rest.len() {
+            0 => return None,
+            n if n >= 3 && parse_op(rest[n - 2]).is_some() => {
+                let (ord, or_equal) = parse_op(rest[n - 2]).unwrap();
+                match Version::parse(rest[n - 1]) {
+                    Some(version) => (&rest[..n - 2], Some((ord, or_equal, version))),
+                    None => return None,
+                }
+            }
+            _ => (rest, None),
+        };
+        let client = client.join(" ");
+        let client = if client == "any" {
+            ClientMatch::Any
+        } else if client.starts_with('-') {
+            ClientMatch::Prefix(client)
+        } else {
+            ClientMatch::Client(client.to_lowercase())
+        };
+        Some(ClientRule {
+            allow: allow,
+            client: client,
+            version: version,
+        })
+    }
+
+    fn matches(&self, id: &PeerId, client: Option<&Client>) -> bool {
+        let matched = match self.client {
+            ClientMatch::Any => true,
+            ClientMatch::Prefix(ref prefix) => id.as_bytes().starts_with(prefix.as_bytes()),
+            ClientMatch::Client(ref name) => {
+                client.is_some_and(|c| {
+                    c.code.to_lowercase() == *name || c.name().to_lowercase() == *name
+                })
+            }
+        };
+        match self.version {
+            Some((ord, or_equal, ref version)) if matched => {
+                client.is_some_and(|c| {
+                    let cmp = c.version.cmp(version);
+                    cmp == ord || (or_equal && cmp == Ordering::Equal)
+                })
+            }
+            _ => matched,
+        }
+    }
+}
+
+fn parse_op(op: &str) -> Option<(Ordering, bool)> {
+    match op {
+        ">=" => Some((Ordering::Greater, true)),
+        ">" => Some((Ordering::Greater, false)),
+        "<=" => Some((Ordering::Less, true)),
+        "<" => Some((Ordering::Less, false)),
+        "=" | "==" => Some((Ordering::Equal, true)),
+        _ => None,
+    }
+}
+
+/// Checks `id` against `rules`, returning a description of the client if it's rejected.
+pub fn check(rules: &[ClientRule], id: &PeerId) -> Result<(), String> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let client = Client::parse(id);
+    let allowed = match rules.iter().find(|r| r.matches(id, client.as_ref())) {
+        Some(rule) => rule.allow,
+        None => !rules.iter().any(|r| r.allow),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(match client {
+            Some(client) => client.to_string(),
+            None => {
+                let prefix: String = String::from_utf8_lossy(&id.as_bytes()[..8])
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .collect();
+                format!("unknown client {}", prefix)
+            }
+        })
+    }
+}
+
+#[test]
+fn client_rules() {
+    let id = |s: &[u8]| PeerId::from_bytes(s).unwrap();
+    let qb = id(b"-qB4250-000000000000");
+    assert!(Client::parse(&qb).unwrap().to_string() == "qBittorrent 4.2.5.0");
+    assert!(Client::parse(&id(b"T03I--00000000000000")).unwrap().to_string() ==
+            "BitTornado 0.3.18");
+    assert!(Client::parse(&id(b"00000000000000000000")).is_none());
+
+    let rules: Vec<_> = ["deny -XL", "allow qBittorrent >= 4.3", "allow TR"]
+        .iter()
+        .map(|r| ClientRule::parse(r).unwrap())
+        .collect();
+    assert!(check(&rules, &qb) == Err(String::from("qBittorrent 4.2.5.0")));
+    assert!(check(&rules, &id(b"-qB4300-000000000000")).is_ok());
+    assert!(check(&rules, &id(b"-TR2940-000000000000")).is_ok());
+    assert!(check(&rules, &id(b"-XL0012-000000000000")) == Err(String::from("Xunlei 0.0.1.2")));
+    assert!(check(&rules, &id(b"-DE13F0-000000000000")).is_err());
+    // Without allow rules, everything not denied is allowed.
+    assert!(check(&rules[..1], &id(b"-DE13F0-000000000000")).is_ok());
+    assert!(ClientRule::parse("allow").is_none() && ClientRule::parse("qB").is_none());
+}
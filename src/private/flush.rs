@@ -0,0 +1,515 @@
+use config::PrivateConfig;
+use tracker::info_hash::InfoHash;
+use tracker::peer::{Delta, Snatch};
+use tracker::peer_id::PeerId;
+use super::SeedTotals;
+
+use rustc_serialize::json::{Json, Object};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+
+/// Everything a user did on a torrent between two flushes, written to the backend as one row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub passkey: String,
+    pub info_hash: InfoHash,
+    pub announces: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub credited_upload: u64,
+    pub credited_download: u64,
+    pub seed_time: u64,
+    pub bonus_points: f64,
+    /// The user's completions of the torrent in these announces.
+    pub snatches: Vec<Snatch>,
+}
+
+/// Rows waiting to be written, kept until the backend accepts them. Failed writes are retried
+/// with exponential backoff, and the rows are spilled to the journal in the meantime so they
+/// survive restarts and don't pile up in memory.
+pub struct Flusher {
+    pending: HashMap<(String, InfoHash), Row>,
+    /// Users and torrents with rows in the journal, which is read back in before every write.
+    journaled: HashSet<(String, InfoHash)>,
+    /// Set while a batch taken out is being written.
+    writing: bool,
+    failures: u32,
+    retry_at: i64,
+    batch_size: usize,
+    max_pending: usize,
+    backoff: u64,
+    max_backoff: u64,
+    journal: Option<String>,
+}
+
+impl Row {
+    fn new(passkey: String, info_hash: InfoHash) -> Row {
+        Row {
+            passkey: passkey,
+            info_hash: info_hash,
+            announces: 0,
+            uploaded: 0,
+            downloaded: 0,
+            credited_upload: 0,
+            credited_download: 0,
+            seed_time: 0,
+            bonus_points: 0.0,
+            snatches: Vec::new(),
+        }
+    }
+
+    fn add_delta(&mut self, delta: &Delta) {
+        self.announces += 1;
+        self.uploaded = self.uploaded.saturating_add(delta.upload);
+        self.downloaded = self.downloaded.saturating_add(delta.download);
+        self.credited_upload = self.credited_upload.saturating_add(delta.credited_upload);
+        self.credited_download = self.credited_download.saturating_add(delta.credited_download);
+        if let Some(ref snatch) = delta.snatch {
+            self.snatches.push(snatch.clone());
+        }
+    }
+
+    fn key(&self) -> (String, InfoHash) {
+        (self.passkey.clone(), self.info_hash)
+    }
+
+    fn merge(&mut self, other: &Row) {
+        self.announces += other.announces;
+        self.uploaded = self.uploaded.saturating_add(other.uploaded);
+        self.downloaded = self.downloaded.saturating_add(other.downloaded);
+        self.credited_upload = self.credited_upload.saturating_add(other.credited_upload);
+        self.credited_download = self.credited_download.saturating_add(other.credited_download);
+        self.seed_time += other.seed_time;
+        self.bonus_points += other.bonus_points;
+        self.snatches.extend_from_slice(&other.snatches);
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut json = Object::new();
+        json.insert(String::from("passkey"), Json::String(self.passkey.clone()));
+        json.insert(String::from("info_hash"), Json::String(self.info_hash.to_hex()));
+        json.insert(String::from("announces"), Json::U64(self.announces));
+        json.insert(String::from("uploaded"), Json::U64(self.uploaded));
+        json.insert(String::from("downloaded"), Json::U64(self.downloaded));
+        json.insert(String::from("credited_upload"), Json::U64(self.credited_upload));
+        json.insert(String::from("credited_download"), Json::U64(self.credited_download));
+        json.insert(String::from("seed_time"), Json::U64(self.seed_time));
+        json.insert(String::from("bonus_points"), Json::F64(self.bonus_points));
+        if !self.snatches.is_empty() {
+            let snatches = self.snatches
+                .iter()
+                .map(|snatch| {
+                    let mut json = Object::new();
+                    json.insert(String::from("peer_id"), Json::String(snatch.peer_id.to_hex()));
+                    if let Some(ip) = snatch.ip {
+                        json.insert(String::from("ip"), Json::String(ip.to_string()));
+                    }
+                    json.insert(String::from("time"), Json::I64(snatch.time));
+                    Json::Object(json)
+                })
+                .collect();
+            json.insert(String::from("snatches"), Json::Array(snatches));
+        }
+        Json::Object(json)
+    }
+
+    pub fn from_json(json: &Json) -> Option<Row> {
+        let passkey = match json.find("passkey").and_then(|v| v.as_string()) {
+            Some(passkey) => String::from(passkey),
+            None => return None,
+        };
+        let info_hash = match json.find("info_hash")
+            .and_then(|v| v.as_string())
+            .and_then(InfoHash::from_hex) {
+            Some(info_hash) => info_hash,
+            None => return None,
+        };
+        let int = |key| json.find(key).and_then(|v| v.as_u64()).unwrap_or(0);
+        Some(Row {
+            announces: int("announces"),
+            uploaded: int("uploaded"),
+            downloaded: int("downloaded"),
+            credited_upload: int("credited_upload"),
+            credited_download: int("credited_download"),
+            seed_time: int("seed_time"),
+            bonus_points: json.find("bonus_points").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            snatches: json.find("snatches")
+                .and_then(|v| v.as_array())
+                .map_or(Vec::new(), |snatches| {
+                    snatches.iter()
+                        .filter_map(|snatch| snatch_from_json(snatch, &passkey, info_hash))
+                        .collect()
+                }),
+            ..Row::new(passkey, info_hash)
+        })
+    }
+}
+
+/// Rows taken out of a `Flusher` to be written without holding on to it.
+pub struct Batch {
+    rows: Vec<Row>,
+    written: usize,
+    batch_size: usize,
+}
+
+/// Reads a snatch of a row back, which only keeps the peer id, IP and time of each.
+fn snatch_from_json(json: &Json, passkey: &str, info_hash: InfoHash) -> Option<Snatch> {
+    let peer_id = match json.find("peer_id")
+        .and_then(|v| v.as_string())
+        .and_then(PeerId::from_hex) {
+        Some(peer_id) => peer_id,
+        None => return None,
+    };
+    json.find("time").and_then(|v| v.as_i64()).map(|time| {
+        Snatch {
+            passkey: Some(String::from(passkey)),
+            info_hash: info_hash,
+            peer_id: peer_id,
+            ip: json.find("ip").and_then(|v| v.as_string()).and_then(|ip| ip.parse().ok()),
+            time: time,
+        }
+    })
+}
+
+/// Sums up deltas and seed totals by passkey and torrent. Deltas without a passkey can't be
+/// credited to anyone and are left out.
+pub fn aggregate(deltas: &[Delta],
+                 seed_totals: HashMap<(String, InfoHash), SeedTotals>)
+                 -> Vec<Row> {
+    let mut rows: HashMap<(String, InfoHash), Row> = HashMap::new();
+    for delta in deltas {
+        if let Some(ref passkey) = delta.passkey {
+            rows.entry((passkey.clone(), delta.info_hash))
+                .or_insert_with(|| Row::new(passkey.clone(), delta.info_hash))
+                .add_delta(delta);
+        }
+    }
+    for ((passkey, info_hash), totals) in seed_totals {
+        let row = rows.entry((passkey.clone(), info_hash))
+            .or_insert_with(|| Row::new(passkey, info_hash));
+        row.seed_time += totals.seed_time;
+        row.bonus_points += totals.bonus_points;
+    }
+    rows.into_values().collect()
+}
+
+impl Flusher {
+    /// Picks up rows journaled before a restart, they're written with the first flush.
+    pub fn new(config: &PrivateConfig) -> Flusher {
+        let mut flusher = Flusher {
+            pending: HashMap::new(),
+            journaled: HashSet::new(),
+            writing: false,
+            failures: 0,
+            retry_at: 0,
+            batch_size: cmp::max(config.flush_batch_size, 1),
+            max_pending: config.max_pending_rows,
+            backoff: cmp::max(config.flush_interval.as_secs(), 1),
+            max_backoff: config.max_flush_backoff,
+            journal: config.flush_journal.clone(),
+        };
+        match flusher.read_journal() {
+            Ok(rows) if !rows.is_empty() => {
+                info!("Replaying {} rows from the flush journal!", rows.len());
+                flusher.journaled = rows.into_iter().map(|row| row.key()).collect();
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to read the flush journal: {}", e),
+        }
+        flusher
+    }
+
+    /// Users and torrents with rows waiting to be written, in memory or in the journal.
+    pub fn queue_depth(&self) -> usize {
+        self.journaled.len() +
+        self.pending.keys().filter(|key| !self.journaled.contains(*key)).count()
+    }
+
+    pub fn journaled(&self) -> usize {
+        self.journaled.len()
+    }
+
+    /// Queues `rows` and writes everything queued in batches using `write`.
+    pub fn flush<F>(&mut self, rows: Vec<Row>, now: i64, write: F)
+        where F: FnMut(&[Row]) -> io::Result<()>
+    {
+        if let Some(mut batch) = self.take(rows, now) {
+            let res = batch.write(write);
+            self.finish(batch, res, now);
+        }
+    }
+
+    /// Queues `rows` and, unless waiting to retry or already writing, takes out everything
+    /// queued to be written. The outcome has to be handed back to `finish`.
+    pub fn take(&mut self, rows: Vec<Row>, now: i64) -> Option<Batch> {
+        self.add_rows(rows);
+        // The journal is left alone until the batch in flight is done with it.
+        if self.writing {
+            return None;
+        }
+        if now < self.retry_at || self.queue_depth() == 0 {
+            self.limit_pending();
+            return None;
+        }
+        if !self.journaled.is_empty() {
+            match self.read_journal() {
+                Ok(rows) => self.add_rows(rows),
+                Err(e) => {
+                    error!("Failed to read the flush journal: {}", e);
+                    self.limit_pending();
+                    return None;
+                }
+            }
+        }
+        self.writing = true;
+        Some(Batch {
+            rows: mem::take(&mut self.pending).into_values().collect(),
+            written: 0,
+            batch_size: self.batch_size,
+        })
+    }
+
+    /// Takes back what's left of `batch`, retrying it after a backoff if writing failed.
+    pub fn finish(&mut self, batch: Batch, res: io::Result<()>, now: i64) {
+        self.writing = false;
+        let mut rows = batch.rows;
+        rows.drain(..batch.written);
+        match res {
+            Ok(()) => {
+                self.failures = 0;
+                self.retry_at = 0;
+                if !self.journaled.is_empty() {
+                    self.remove_journal();
+                }
+                self.limit_pending();
+            }
+            Err(e) => {
+                self.failures += 1;
+                let backoff = self.backoff.saturating_mul(1 << cmp::min(self.failures - 1, 20));
+                let backoff = cmp::min(backoff, cmp::max(self.max_backoff, self.backoff));
+                self.retry_at = now + backoff as i64;
+                warn!("Failed to flush {} rows, retrying in {}s: {}", rows.len(), backoff, e);
+                self.add_rows(rows);
+                // Whatever's journaled now is also back in memory, and replaced by the spill.
+                if self.journal.is_some() {
+                    self.spill();
+                } else {
+                    self.journaled.clear();
+                    self.limit_pending();
+                }
+            }
+        }
+    }
+
+    fn add_rows(&mut self, rows: Vec<Row>) {
+        for row in rows {
+            match self.pending.entry(row.key()) {
+                Entry::Occupied(mut e) => e.get_mut().merge(&row),
+                Entry::Vacant(e) => {
+                    e.insert(row);
+                }
+            }
+        }
+    }
+
+    /// Keeps memory bounded while the backend is down, by spilling to the journal or, without
+    /// one, dropping rows.
+    fn limit_pending(&mut self) {
+        if self.max_pending == 0 || self.pending.len() <= self.max_pending {
+            return;
+        }
+        if self.journal.is_some() && self.journaled.is_empty() {
+            self.spill();
+            return;
+        }
+        if self.journal.is_some() && self.append_journal().is_ok() {
+            return;
+        }
+        let excess = self.pending.len() - self.max_pending;
+        error!("Flush queue is full, dropping {} rows!", excess);
+        let dropped: Vec<_> = self.pending.keys().take(excess).cloned().collect();
+        for key in dropped {
+            self.pending.remove(&key);
+        }
+    }
+
+    /// Replaces the journal with everything pending, leaving nothing in memory.
+    fn spill(&mut self) {
+        let path = match self.journal {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let tmp = format!("{}.tmp", path);
+        let res = File::create(&tmp)
+            .and_then(|mut file| {
+                for row in self.pending.values() {
+                    try!(writeln!(file, "{}", row.to_json()));
+                }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path));
+        match res {
+            Ok(()) => {
+                self.journaled = self.pending.drain().map(|(key, _)| key).collect();
+            }
+            Err(e) => error!("Failed to write the flush journal: {}", e),
+        }
+    }
+
+    /// Adds everything pending to the end of the journal.
+    fn append_journal(&mut self) -> io::Result<()> {
+        let path = match self.journal {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let res = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .and_then(|mut file| {
+                for row in self.pending.values() {
+                    try!(writeln!(file, "{}", row.to_json()));
+                }
+                file.sync_all()
+            });
+        match res {
+            Ok(()) => {
+                self.journaled.extend(self.pending.drain().map(|(key, _)| key));
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to append to the flush journal: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_journal(&self) -> io::Result<Vec<Row>> {
+        let path = match self.journal {
+            Some(ref path) => path,
+            None => return Ok(Vec::new()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut rows = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            match Json::from_str(&line).ok().as_ref().and_then(Row::from_json) {
+                Some(row) => rows.push(row),
+                None => warn!("Skipping malformed flush journal line {:?}!", line),
+            }
+        }
+        Ok(rows)
+    }
+
+    fn remove_journal(&mut self) {
+        if let Some(ref path) = self.journal {
+            if let Err(e) = fs::remove_file(path) {
+                error!("Failed to remove the flush journal: {}", e);
+                return;
+            }
+        }
+        self.journaled.clear();
+    }
+}
+
+impl Batch {
+    /// Writes the rows in batches using `write`, stopping at the first failure.
+    pub fn write<F>(&mut self, mut write: F) -> io::Result<()>
+        where F: FnMut(&[Row]) -> io::Result<()>
+    {
+        for batch in self.rows[self.written..].chunks(self.batch_size) {
+            try!(write(batch));
+            self.written += batch.len();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn batched_flush() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    let info_hash = InfoHash::from_bytes(&[0; 20]).unwrap();
+    let peer_id = PeerId::from_bytes(b"-TR2940-000000000000").unwrap();
+    let delta = Delta {
+        info_hash: info_hash,
+        peer_id: peer_id,
+        upload: 10,
+        download: 100,
+        credited_upload: 10,
+        credited_download: 0,
+        left: 0,
+        interval: 0,
+        seed_time: 0,
+        seeders: 1,
+        passkey: Some(String::from("abc")),
+        snatch: None,
+        suspicions: Vec::new(),
+    };
+    let mut deltas = vec![delta.clone(); 100];
+    let snatch = Snatch {
+        passkey: delta.passkey.clone(),
+        info_hash: info_hash,
+        peer_id: peer_id,
+        ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+        time: 5,
+    };
+    deltas[50].snatch = Some(snatch.clone());
+    let mut seed_totals = HashMap::new();
+    seed_totals.insert((String::from("abc"), info_hash),
+                       SeedTotals {
+                           seed_time: 60,
+                           bonus_points: 0.5,
+                       });
+    let rows = aggregate(&deltas, seed_totals);
+    assert!(rows.len() == 1);
+    assert!(rows[0].announces == 100 && rows[0].uploaded == 1000 && rows[0].downloaded == 10000);
+    assert!(rows[0].credited_download == 0 && rows[0].snatches == vec![snatch.clone()]);
+    assert!(rows[0].seed_time == 60 && rows[0].bonus_points == 0.5);
+    assert!(Row::from_json(&rows[0].to_json()).as_ref() == Some(&rows[0]));
+
+    let journal = format!("{}/sanka_flush_test_{}.jsonl",
+                          ::std::env::temp_dir().display(),
+                          ::std::process::id());
+    let config = PrivateConfig {
+        flush_interval: ::std::time::Duration::from_secs(10),
+        max_flush_backoff: 30,
+        flush_journal: Some(journal.clone()),
+        ..Default::default()
+    };
+    let mut flusher = Flusher::new(&config);
+    let down = |_: &[Row]| Err(io::Error::new(io::ErrorKind::InvalidData, "down"));
+    flusher.flush(rows.clone(), 0, down);
+    assert!(flusher.journaled() == 1 && flusher.queue_depth() == 1);
+    // Nothing is tried before the backoff is over, which doubles with each failure.
+    flusher.flush(rows.clone(), 9, |_| panic!("retried too early"));
+    // Rows both in memory and in the journal are counted once.
+    assert!(flusher.journaled() == 1 && flusher.queue_depth() == 1);
+    flusher.flush(Vec::new(), 10, down);
+    flusher.flush(Vec::new(), 29, |_| panic!("retried too early"));
+    // Rows of the same user and torrent keep being merged in the journal.
+    assert!(flusher.journaled() == 1 && flusher.queue_depth() == 1);
+
+    // A restart picks the journal back up and replays it with the first flush.
+    let mut flusher = Flusher::new(&config);
+    assert!(flusher.queue_depth() == 1);
+    let mut written = Vec::new();
+    flusher.flush(rows.clone(),
+                  30,
+                  |batch| {
+                      written.extend_from_slice(batch);
+                      Ok(())
+                  });
+    assert!(written.len() == 1 && written[0].announces == 300);
+    // Every snatch makes it through the journal.
+    assert!(written[0].snatches == vec![snatch.clone(), snatch.clone(), snatch]);
+    assert!(flusher.queue_depth() == 0 && fs::metadata(&journal).is_err());
+}
@@ -0,0 +1,127 @@
+use config::PrivateConfig;
+use tracker::info_hash::InfoHash;
+use tracker::peer::Delta;
+
+/// What a user transferred on a torrent they downloaded from, kept until they've seeded it
+/// for long enough.
+#[derive(Clone, Debug)]
+pub struct SeedRecord {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// When the user completed the torrent, in seconds since the Unix epoch.
+    pub completed: Option<i64>,
+    /// Seconds spent seeding since completing.
+    pub seed_time: u64,
+    pub last_seen: i64,
+}
+
+/// A user who completed a torrent and stopped seeding it too early.
+#[derive(Clone, Debug)]
+pub struct HitAndRun {
+    pub passkey: String,
+    pub info_hash: InfoHash,
+    pub record: SeedRecord,
+}
+
+/// Hit-and-runs are tracked once either a minimum seed time or a minimum ratio is set.
+pub fn is_enabled(config: &PrivateConfig) -> bool {
+    config.hnr_min_seed_time > 0 || config.hnr_min_ratio > 0.0
+}
+
+impl SeedRecord {
+    pub fn new(now: i64) -> SeedRecord {
+        SeedRecord {
+            uploaded: 0,
+            downloaded: 0,
+            completed: None,
+            seed_time: 0,
+            last_seen: now,
+        }
+    }
+
+    pub fn add_delta(&mut self, delta: &Delta, now: i64) {
+        self.uploaded = self.uploaded.saturating_add(delta.upload);
+        self.downloaded = self.downloaded.saturating_add(delta.download);
+        if let Some(ref snatch) = delta.snatch {
+            self.completed = Some(snatch.time);
+        }
+        if self.completed.is_some() {
+            self.seed_time += delta.seed_time;
+        }
+        self.last_seen = now;
+    }
+
+    /// Whether the user seeded for the minimum time or up to the minimum ratio, whichever
+    /// are set.
+    pub fn is_satisfied(&self, config: &PrivateConfig) -> bool {
+        let ratio = self.uploaded as f64 / self.downloaded as f64;
+        (config.hnr_min_seed_time > 0 && self.seed_time >= config.hnr_min_seed_time) ||
+        (config.hnr_min_ratio > 0.0 && self.downloaded > 0 && ratio >= config.hnr_min_ratio)
+    }
+
+    /// Completed, not satisfied, and not announced for longer than the grace period.
+    pub fn is_hit_and_run(&self, config: &PrivateConfig, now: i64) -> bool {
+        self.completed.is_some() && !self.is_satisfied(config) &&
+        now - self.last_seen > config.hnr_grace as i64
+    }
+
+    /// Records which can't become hit-and-runs anymore: satisfied ones and abandoned
+    /// downloads.
+    pub fn is_done(&self, config: &PrivateConfig, now: i64) -> bool {
+        match self.completed {
+            Some(_) => self.is_satisfied(config),
+            None => now - self.last_seen > config.hnr_grace as i64,
+        }
+    }
+}
+
+#[test]
+fn hit_and_runs() {
+    use tracker::peer::Snatch;
+    use tracker::peer_id::PeerId;
+
+    let config = PrivateConfig {
+        hnr_min_seed_time: 3600,
+        hnr_min_ratio: 1.0,
+        hnr_grace: 100,
+        ..Default::default()
+    };
+    let info_hash = InfoHash::from_bytes(&[0; 20]).unwrap();
+    let peer_id = PeerId::from_bytes(b"-TR2940-000000000000").unwrap();
+    let mut delta = Delta {
+        info_hash: info_hash,
+        peer_id: peer_id,
+        upload: 10,
+        download: 100,
+        credited_upload: 10,
+        credited_download: 100,
+        left: 0,
+        interval: 0,
+        seed_time: 0,
+        seeders: 1,
+        passkey: None,
+        snatch: Some(Snatch {
+            passkey: None,
+            info_hash: info_hash,
+            peer_id: peer_id,
+            ip: None,
+            time: 0,
+        }),
+        suspicions: Vec::new(),
+    };
+    let mut record = SeedRecord::new(0);
+    record.add_delta(&delta, 0);
+    assert!(!record.is_hit_and_run(&config, 100) && record.is_hit_and_run(&config, 101));
+
+    // Seeding long enough resolves it, as does uploading enough.
+    delta.snatch = None;
+    delta.seed_time = 3600;
+    let mut seeded = record.clone();
+    seeded.add_delta(&delta, 0);
+    assert!(!seeded.is_hit_and_run(&config, 1000) && seeded.is_done(&config, 0));
+    delta.seed_time = 0;
+    delta.upload = 90;
+    delta.download = 0;
+    record.add_delta(&delta, 0);
+    assert!(!record.is_hit_and_run(&config, 1000));
+}
@@ -0,0 +1,137 @@
+use config::PrivateConfig;
+use tracker::announce::{Action, Announce};
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use response::error::ErrorResponse;
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// A user's peers currently in swarms, by the swarm they're in.
+#[derive(Debug, Default)]
+pub struct ActivePeers {
+    peers: HashMap<(InfoHash, PeerId), ActivePeer>,
+}
+
+#[derive(Debug)]
+struct ActivePeer {
+    ip: Option<IpAddr>,
+    seeding: bool,
+}
+
+impl ActivePeers {
+    pub fn update(&mut self, hash: &InfoHash, a: &Announce) {
+        let key = (*hash, a.peer_id);
+        match a.action {
+            Action::Stopped => {
+                self.peers.remove(&key);
+            }
+            _ => {
+                self.peers.insert(key,
+                                  ActivePeer {
+                                      ip: announce_ip(a),
+                                      seeding: a.left == 0,
+                                  });
+            }
+        }
+    }
+
+    pub fn remove(&mut self, hash: &InfoHash, peer_id: &PeerId) {
+        self.peers.remove(&(*hash, *peer_id));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Checks whether the announce would take the user past one of the limits. Limits only
+    /// apply to what the announce adds, so users already past them aren't cut off halfway.
+    pub fn check(&self,
+                 hash: &InfoHash,
+                 a: &Announce,
+                 config: &PrivateConfig)
+                 -> Option<ErrorResponse> {
+        if let Action::Stopped = a.action {
+            return None;
+        }
+        if let (Some(ip), true) = (announce_ip(a), config.max_ips > 0) {
+            let ips: HashSet<_> = self.peers.values().filter_map(|p| p.ip).collect();
+            if !ips.contains(&ip) && ips.len() >= config.max_ips {
+                return Some(ErrorResponse::IpLimit(config.max_ips));
+            }
+        }
+        let existing = self.peers.get(&(*hash, a.peer_id));
+        if a.left > 0 && config.max_leeching > 0 {
+            let leeching: HashSet<_> = self.peers
+                .iter()
+                .filter(|&(_, p)| !p.seeding)
+                .map(|(&(h, _), _)| h)
+                .collect();
+            if !leeching.contains(hash) && leeching.len() >= config.max_leeching {
+                return Some(ErrorResponse::LeechLimit(config.max_leeching));
+            }
+        }
+        let already_seeding = existing.is_some_and(|p| p.seeding);
+        if a.left == 0 && config.max_seeds_per_torrent > 0 && !already_seeding {
+            let seeds = self.peers
+                .iter()
+                .filter(|&(&(h, _), p)| h == *hash && p.seeding)
+                .count();
+            if seeds >= config.max_seeds_per_torrent {
+                return Some(ErrorResponse::SeedLimit(config.max_seeds_per_torrent));
+            }
+        }
+        None
+    }
+}
+
+fn announce_ip(a: &Announce) -> Option<IpAddr> {
+    a.ipv4
+        .map(|addr| IpAddr::V4(*addr.ip()))
+        .or_else(|| a.ipv6.map(|addr| IpAddr::V6(*addr.ip())))
+}
+
+#[test]
+fn peer_limits() {
+    use tracker::announce::test_announce;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let config = PrivateConfig {
+        max_leeching: 1,
+        max_ips: 2,
+        max_seeds_per_torrent: 1,
+        ..Default::default()
+    };
+    let announce = |hash: u8, peer: u8, ip: u8, left: u64| {
+        Announce {
+            passkey: Some(String::from("key")),
+            ipv4: Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, ip), 6881)),
+            left: left,
+            action: if left > 0 { Action::Leeching } else { Action::Seeding },
+            ..test_announce(hash, peer)
+        }
+    };
+    let check = |peers: &ActivePeers, a: &Announce| peers.check(&a.info_hash, a, &config);
+    let mut peers = ActivePeers::default();
+    let leech = announce(1, 1, 1, 10);
+    assert!(check(&peers, &leech).is_none());
+    peers.update(&leech.info_hash, &leech);
+    assert!(check(&peers, &leech).is_none());
+    match check(&peers, &announce(2, 2, 1, 10)) {
+        Some(ErrorResponse::LeechLimit(1)) => {}
+        e => panic!("expected the leech limit, got {:?}", e),
+    }
+
+    let seed = announce(3, 3, 2, 0);
+    peers.update(&seed.info_hash, &seed);
+    match check(&peers, &announce(3, 4, 2, 0)) {
+        Some(ErrorResponse::SeedLimit(1)) => {}
+        e => panic!("expected the seed limit, got {:?}", e),
+    }
+    match check(&peers, &announce(4, 5, 3, 0)) {
+        Some(ErrorResponse::IpLimit(2)) => {}
+        e => panic!("expected the IP limit, got {:?}", e),
+    }
+    peers.remove(&seed.info_hash, &seed.peer_id);
+    assert!(check(&peers, &announce(4, 5, 3, 0)).is_none());
+}
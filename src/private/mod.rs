@@ -1,18 +1,63 @@
+pub mod bonus;
+pub mod client;
+pub mod flush;
+pub mod hnr;
+pub mod limits;
+pub mod modifier;
+pub mod registry;
+pub mod user;
+
 use config::PrivateConfig;
-use tracker::peer::Delta;
-use tracker::announce::Announce;
+use tracker::peer::{Credit, Delta, Peer, Suspicion};
+use tracker::announce::{Action, Announce};
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
 use response::error::ErrorResponse;
+use self::client::ClientRule;
+use self::flush::{Flusher, Row};
+use self::hnr::{HitAndRun, SeedRecord};
+use self::limits::ActivePeers;
+use self::modifier::Modifier;
+use self::registry::{TorrentRecord, TorrentRegistry, TorrentStatus};
+use self::user::User;
 
 use parking_lot::{Mutex, RwLock};
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time;
+
+/// Seeding by a user on a torrent.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SeedTotals {
+    pub seed_time: u64,
+    pub bonus_points: f64,
+}
 
 #[allow(dead_code, unused_variables)]
 pub struct PrivateTracker {
     deltas: Mutex<Vec<Delta>>,
-    torrents: RwLock<HashSet<String>>,
-    peers: RwLock<Vec<String>>,
+    /// Suspicious deltas, held back from flushing until reviewed.
+    quarantine: Mutex<VecDeque<Delta>>,
+    torrents: RwLock<TorrentRegistry>,
+    clients: RwLock<Vec<ClientRule>>,
     passkeys: RwLock<HashSet<String>>,
+    /// Totals by passkey, as loaded from the backend plus whatever was credited since.
+    users: RwLock<HashMap<String, User>>,
+    /// Modifiers applying to every torrent.
+    global_modifiers: RwLock<Vec<Modifier>>,
+    /// Transfers by passkey and torrent, for finding hit-and-runs.
+    seeding: RwLock<HashMap<String, HashMap<InfoHash, SeedRecord>>>,
+    /// Peers in swarms by passkey, for the per user limits.
+    active: RwLock<HashMap<String, ActivePeers>>,
+    /// Seed time and bonus points by passkey and torrent since the last flush.
+    seed_totals: Mutex<HashMap<(String, InfoHash), SeedTotals>>,
+    flusher: Mutex<Flusher>,
+    /// Rows waiting to be written as of the last flush, and how many of them are journaled.
+    queued_rows: AtomicUsize,
+    journaled_rows: AtomicUsize,
     pub config: PrivateConfig
 }
 
@@ -26,22 +71,111 @@ impl Default for PrivateTracker {
 impl PrivateTracker {
     pub fn new(config: PrivateConfig) -> PrivateTracker {
         let deltas = Mutex::new(Default::default());
+        let quarantine = Mutex::new(Default::default());
         let torrents = RwLock::new(Default::default());
-        let peers = RwLock::new(Default::default());
+        let clients = RwLock::new(config.clients.clone());
         let passkeys = RwLock::new(Default::default());
+        let users = RwLock::new(Default::default());
+        let global_modifiers = RwLock::new(config.modifiers.clone());
+        let seeding = RwLock::new(Default::default());
+        let active = RwLock::new(Default::default());
+        let seed_totals = Mutex::new(Default::default());
+        let flusher = Flusher::new(&config);
+        let queued_rows = AtomicUsize::new(flusher.queue_depth());
+        let journaled_rows = AtomicUsize::new(flusher.journaled());
+        let flusher = Mutex::new(flusher);
 
+        // All torrents are loaded into `torrents` here, see `load_torrents`, and the totals of
+        // every user into `users`, see `load_users`.
         // Fill in implementation here
 
         PrivateTracker {
             deltas: deltas,
+            quarantine: quarantine,
             torrents: torrents,
-            peers: peers,
+            clients: clients,
             passkeys: passkeys,
+            users: users,
+            global_modifiers: global_modifiers,
+            seeding: seeding,
+            active: active,
+            seed_totals: seed_totals,
+            flusher: flusher,
+            queued_rows: queued_rows,
+            journaled_rows: journaled_rows,
             config: config
         }
     }
 
+    /// Checks the delta against the configured limits, adding suspicions and dropping seed
+    /// time measured over implausibly long intervals.
+    pub fn check_delta(&self, delta: &mut Delta) {
+        if delta.seed_time > self.config.max_seed_interval {
+            delta.seed_time = 0;
+        }
+        let rate = delta.upload / cmp::max(delta.interval, 1);
+        if self.config.max_upload_rate > 0 && rate > self.config.max_upload_rate {
+            delta.suspicions.push(Suspicion::UploadRate(rate));
+        }
+    }
+
     pub fn add_announce(&self, delta: Delta) {
+        if delta.is_suspicious() {
+            // Without a passkey there's nobody to credit it to after review.
+            if delta.passkey.is_none() || self.config.max_quarantine == 0 {
+                return;
+            }
+            let mut quarantine = self.quarantine.lock();
+            if quarantine.len() >= self.config.max_quarantine {
+                warn!("Quarantine is full, dropping the oldest announce!");
+                quarantine.pop_front();
+            }
+            quarantine.push_back(delta);
+            return;
+        }
+        self.credit_delta(delta);
+    }
+
+    pub fn quarantined(&self) -> Vec<Delta> {
+        self.quarantine.lock().iter().cloned().collect()
+    }
+
+    /// Hands every quarantined delta to `review`, approved ones are credited as usual,
+    /// rejected ones are dropped and those it returns `None` for are kept.
+    pub fn review_quarantine<F: FnMut(&Delta) -> Option<bool>>(&self, mut review: F) {
+        let mut approved = Vec::new();
+        self.quarantine.lock().retain(|delta| {
+            match review(delta) {
+                Some(true) => {
+                    approved.push(delta.clone());
+                    false
+                }
+                Some(false) => false,
+                None => true,
+            }
+        });
+        for delta in approved {
+            self.credit_delta(delta);
+        }
+    }
+
+    fn credit_delta(&self, delta: Delta) {
+        if let Some(ref passkey) = delta.passkey {
+            let mut users = self.users.write();
+            if let Some(user) = users.get_mut(passkey) {
+                user.add_delta(&delta);
+            } else {
+                let mut user = User::default();
+                user.add_delta(&delta);
+                users.insert(passkey.clone(), user);
+            }
+            if hnr::is_enabled(&self.config) {
+                self.record_seeding(passkey, &delta);
+            }
+            if delta.seed_time > 0 {
+                self.add_seed_time(passkey, &delta);
+            }
+        }
         let mut deltas = self.deltas.lock();
         deltas.push(delta);
     }
@@ -52,30 +186,328 @@ impl PrivateTracker {
             let mut old_deltas = self.deltas.lock();
             mem::swap(&mut deltas, &mut *old_deltas);
         }
-        // Fill in implementation here
+        let seed_totals = mem::take(&mut *self.seed_totals.lock());
+        let rows = flush::aggregate(&deltas, seed_totals);
+        let now = time::get_time().sec;
+        // The backend is written to without holding the flusher.
+        let batch = self.flusher.lock().take(rows, now);
+        let flusher = match batch {
+            Some(mut batch) => {
+                let res = batch.write(|rows| self.write_rows(rows));
+                let mut flusher = self.flusher.lock();
+                flusher.finish(batch, res, now);
+                flusher
+            }
+            None => self.flusher.lock(),
+        };
+        self.queued_rows.store(flusher.queue_depth(), Ordering::Relaxed);
+        self.journaled_rows.store(flusher.journaled(), Ordering::Relaxed);
     }
 
-    pub fn validate_passkey(&self, passkey: &String) -> bool {
+    /// Writes a batch of rows to the backend, the whole batch is retried if this fails.
+    fn write_rows(&self, rows: &[Row]) -> io::Result<()> {
         // Fill in implementation here
-        true
+        Ok(())
     }
 
-    pub fn validate_peer(&self, id: &String) -> bool {
-        // Fill in implementation here
-        true
+    /// Deltas not flushed yet, and rows waiting to be written and journaled.
+    pub fn queue_depth(&self) -> (usize, usize, usize) {
+        (self.deltas.lock().len(),
+         self.queued_rows.load(Ordering::Relaxed),
+         self.journaled_rows.load(Ordering::Relaxed))
     }
 
-    pub fn validate_torrent(&self, hash: &String) -> bool {
+    pub fn validate_passkey(&self, passkey: &str) -> bool {
         // Fill in implementation here
         true
     }
 
-    pub fn validate_announce(&self, announce: &Announce) -> Option<ErrorResponse> {
-        // Fill in implementation here
+    pub fn validate_peer(&self, id: &PeerId) -> Option<ErrorResponse> {
+        client::check(&self.clients.read(), id).err().map(ErrorResponse::BadClient)
+    }
+
+    /// Checks that the swarm `hash` belongs to an approved torrent of the site.
+    pub fn validate_torrent(&self, hash: &InfoHash) -> Option<ErrorResponse> {
+        let torrents = self.torrents.read();
+        if !torrents.loaded {
+            return None;
+        }
+        match torrents.get(hash).map(|t| t.status) {
+            Some(TorrentStatus::Approved) => None,
+            Some(TorrentStatus::Pending) => Some(ErrorResponse::PendingTorrent),
+            Some(TorrentStatus::Deleted) => Some(ErrorResponse::DeletedTorrent),
+            None => Some(ErrorResponse::UnregisteredTorrent),
+        }
+    }
+
+    /// Checks an announce to the swarm `hash` against the user's restrictions and limits.
+    pub fn validate_announce(&self, hash: &InfoHash, announce: &Announce) -> Option<ErrorResponse> {
+        // Seeding and stopping are always allowed, so users can work their ratio back up.
+        let leeching = match announce.action {
+            Action::Leeching => announce.left > 0,
+            _ => false,
+        };
+        if leeching {
+            if let Some(user) = self.get_user(announce) {
+                if user.is_ratio_restricted(&self.config) {
+                    return Some(ErrorResponse::LowRatio {
+                        ratio: user.ratio(),
+                        required: self.config.min_ratio,
+                    });
+                }
+            }
+            if let Some(ref passkey) = announce.passkey {
+                if self.config.hnr_max > 0 {
+                    let hnrs = self.count_hit_and_runs(passkey, time::get_time().sec);
+                    if hnrs >= self.config.hnr_max {
+                        return Some(ErrorResponse::HitAndRuns(hnrs));
+                    }
+                }
+            }
+        }
+        if let Some(ref passkey) = announce.passkey {
+            if let Some(peers) = self.active.read().get(passkey) {
+                return peers.check(hash, announce, &self.config);
+            }
+        }
         None
     }
 
+    /// A warning for the announcing user, sent along with successful announces.
+    pub fn warning_message(&self, announce: &Announce) -> Option<String> {
+        self.get_user(announce).and_then(|user| user.ratio_warning(&self.config))
+    }
+
+    /// Keeps track of the announcing peer for the per user limits, `hash` being the swarm
+    /// it's in.
+    pub fn index_peer(&self, hash: &InfoHash, announce: &Announce) {
+        let passkey = match announce.passkey {
+            Some(ref passkey) => passkey,
+            None => return,
+        };
+        let mut active = self.active.write();
+        if let Some(peers) = active.get_mut(passkey) {
+            peers.update(hash, announce);
+            if peers.is_empty() {
+                active.remove(passkey);
+            }
+            return;
+        }
+        let mut peers = ActivePeers::default();
+        peers.update(hash, announce);
+        if !peers.is_empty() {
+            active.insert(passkey.clone(), peers);
+        }
+    }
+
+    /// Forgets peers which left the swarm without stopping.
+    pub fn remove_peers(&self, hash: &InfoHash, peers: &[Peer]) {
+        if peers.is_empty() {
+            return;
+        }
+        let mut active = self.active.write();
+        for peer in peers {
+            let passkey = match peer.passkey {
+                Some(ref passkey) => passkey,
+                None => continue,
+            };
+            let now_empty = match active.get_mut(passkey) {
+                Some(user_peers) => {
+                    user_peers.remove(hash, &peer.id);
+                    user_peers.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                active.remove(passkey);
+            }
+        }
+    }
+
+    fn add_seed_time(&self, passkey: &str, delta: &Delta) {
+        let points = if self.config.bonus.is_enabled() {
+            let size = self.torrents.read().get(&delta.info_hash).map_or(0, |t| t.size);
+            self.config.bonus.points(size, delta.seeders, delta.seed_time)
+        } else {
+            0.0
+        };
+        let mut seed_totals = self.seed_totals.lock();
+        let totals = seed_totals.entry((String::from(passkey), delta.info_hash)).or_default();
+        totals.seed_time += delta.seed_time;
+        totals.bonus_points += points;
+    }
+
+    fn record_seeding(&self, passkey: &str, delta: &Delta) {
+        let now = time::get_time().sec;
+        let mut seeding = self.seeding.write();
+        if let Some(record) = seeding.get_mut(passkey).and_then(|t| t.get_mut(&delta.info_hash)) {
+            record.add_delta(delta, now);
+            return;
+        }
+        // Only downloads can turn into hit-and-runs.
+        if delta.download > 0 || delta.snatch.is_some() {
+            let mut record = SeedRecord::new(now);
+            record.add_delta(delta, now);
+            seeding.entry(String::from(passkey))
+                .or_default()
+                .insert(delta.info_hash, record);
+        }
+    }
+
+    /// Every current hit-and-run.
+    pub fn hit_and_runs(&self) -> Vec<HitAndRun> {
+        let now = time::get_time().sec;
+        let mut hnrs = Vec::new();
+        for (passkey, torrents) in self.seeding.read().iter() {
+            for (info_hash, record) in torrents {
+                if record.is_hit_and_run(&self.config, now) {
+                    hnrs.push(HitAndRun {
+                        passkey: passkey.clone(),
+                        info_hash: *info_hash,
+                        record: record.clone(),
+                    });
+                }
+            }
+        }
+        hnrs
+    }
+
+    fn count_hit_and_runs(&self, passkey: &str, now: i64) -> usize {
+        self.seeding
+            .read()
+            .get(passkey)
+            .map_or(0, |torrents| {
+                torrents.values().filter(|r| r.is_hit_and_run(&self.config, now)).count()
+            })
+    }
+
+    /// The credit of transfers in the swarm `hash` at `now`, from global and per torrent modifiers.
+    pub fn credit(&self, hash: &InfoHash, now: i64) -> Credit {
+        let credit = modifier::credit(&self.global_modifiers.read(), now);
+        match self.torrents.read().get(hash) {
+            Some(torrent) => credit.combine(modifier::credit(&torrent.modifiers, now)),
+            None => credit,
+        }
+    }
+
+    /// Replaces the registry with every torrent of the site.
+    pub fn load_torrents(&self, records: Vec<TorrentRecord>) {
+        *self.torrents.write() = TorrentRegistry::load(records);
+    }
+
+    /// Replaces the users' totals with the ones stored by the backend, which ratios are
+    /// enforced on. Without them totals start at zero with every restart.
+    pub fn load_users(&self, users: HashMap<String, User>) {
+        *self.users.write() = users;
+    }
+
+    /// Adds or replaces the records of torrents which changed on the site.
+    pub fn update_torrents(&self, records: Vec<TorrentRecord>) {
+        self.torrents.write().apply(records);
+    }
+
+    pub fn set_global_modifiers(&self, modifiers: Vec<Modifier>) {
+        *self.global_modifiers.write() = modifiers;
+    }
+
+    fn get_user(&self, announce: &Announce) -> Option<User> {
+        announce.passkey.as_ref().and_then(|passkey| self.users.read().get(passkey).cloned())
+    }
+
     pub fn update(&self) {
+        let now = time::get_time().sec;
+        self.global_modifiers.write().retain(|m| !m.is_expired(now));
+        self.torrents.write().expire_modifiers(now);
+        let mut seeding = self.seeding.write();
+        for torrents in seeding.values_mut() {
+            torrents.retain(|_, record| !record.is_done(&self.config, now));
+        }
+        seeding.retain(|_, torrents| !torrents.is_empty());
+        drop(seeding);
+        // Torrents changed since `self.torrents.read().updated` go to `update_torrents`.
         // Fill in implementation here
     }
 }
+
+#[test]
+fn quarantine_review() {
+    use tracker::announce::test_announce;
+
+    let tracker = PrivateTracker::new(PrivateConfig { max_quarantine: 2, ..Default::default() });
+    let suspicious = |peer: u8, passkey: Option<&str>| {
+        let announce = Announce { passkey: passkey.map(String::from), ..test_announce(0, peer) };
+        Delta {
+            credited_upload: 100,
+            suspicions: vec![Suspicion::UploadWithoutLeechers],
+            ..Delta::new(&announce)
+        }
+    };
+    tracker.add_announce(suspicious(1, None));
+    for peer in 1..4 {
+        tracker.add_announce(suspicious(peer, Some(if peer == 3 { "b" } else { "a" })));
+    }
+    // Anonymous deltas are dropped right away, and the oldest once it's full.
+    let quarantined = tracker.quarantined();
+    assert!(quarantined.len() == 2 && quarantined[0].peer_id == suspicious(2, None).peer_id);
+
+    tracker.review_quarantine(|d| d.passkey.as_ref().filter(|p| *p == "a").map(|_| true));
+    assert!(tracker.users.read().get("a").unwrap().uploaded == 100);
+    assert!(tracker.quarantined().len() == 1);
+    tracker.review_quarantine(|_| Some(false));
+    assert!(tracker.quarantined().is_empty() && tracker.users.read().get("b").is_none());
+}
+
+#[test]
+fn torrents_checked_once_loaded() {
+    let tracker = PrivateTracker::default();
+    let hash = InfoHash::from_bytes(&[1; 20]).unwrap();
+    assert!(tracker.validate_torrent(&hash).is_none());
+    tracker.load_torrents(Vec::new());
+    match tracker.validate_torrent(&hash) {
+        Some(ErrorResponse::UnregisteredTorrent) => {}
+        e => panic!("expected an unregistered torrent, got {:?}", e),
+    }
+}
+
+#[test]
+fn ratio_only_hit_and_runs() {
+    use tracker::announce::test_announce;
+    use tracker::peer::Snatch;
+
+    let tracker = PrivateTracker::new(PrivateConfig { hnr_min_ratio: 1.0, ..Default::default() });
+    let announce = Announce { passkey: Some(String::from("a")), ..test_announce(0, 1) };
+    let snatch = Snatch {
+        passkey: announce.passkey.clone(),
+        info_hash: announce.info_hash,
+        peer_id: announce.peer_id,
+        ip: None,
+        time: 0,
+    };
+    let is_hit_and_run = || {
+        let later = time::get_time().sec + tracker.config.hnr_grace as i64 + 1;
+        tracker.seeding.read()["a"][&announce.info_hash].is_hit_and_run(&tracker.config, later)
+    };
+    tracker.add_announce(Delta { download: 100, snatch: Some(snatch), ..Delta::new(&announce) });
+    assert!(is_hit_and_run());
+    // Uploading up to the ratio settles it.
+    tracker.add_announce(Delta { upload: 100, ..Delta::new(&announce) });
+    assert!(!is_hit_and_run());
+}
+
+#[test]
+fn ratios_survive_restarts() {
+    use tracker::announce::test_announce;
+
+    let config = PrivateConfig { min_ratio: 0.5, ..Default::default() };
+    let tracker = PrivateTracker::new(config);
+    let announce = Announce { passkey: Some(String::from("a")), ..test_announce(0, 1) };
+    let hash = announce.info_hash;
+    assert!(tracker.validate_announce(&hash, &announce).is_none());
+    let mut users = HashMap::new();
+    users.insert(String::from("a"), User { uploaded: 0, downloaded: 1 << 40 });
+    tracker.load_users(users);
+    match tracker.validate_announce(&hash, &announce) {
+        Some(ErrorResponse::LowRatio { .. }) => {}
+        e => panic!("expected a low ratio, got {:?}", e),
+    }
+}
@@ -0,0 +1,95 @@
+use tracker::peer::Credit;
+
+use toml::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModifierKind {
+    /// Downloads aren't counted.
+    Freeleech,
+    /// Neither uploads nor downloads are counted.
+    Neutral,
+    DoubleUpload,
+}
+
+/// Changes how much of a transfer is credited, optionally only between two times.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modifier {
+    pub kind: ModifierKind,
+    /// Seconds since the Unix epoch.
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl ModifierKind {
+    pub fn parse(kind: &str) -> Option<ModifierKind> {
+        match kind {
+            "freeleech" => Some(ModifierKind::Freeleech),
+            "neutral" => Some(ModifierKind::Neutral),
+            "double_upload" => Some(ModifierKind::DoubleUpload),
+            _ => None,
+        }
+    }
+
+    fn credit(&self) -> Credit {
+        match *self {
+            ModifierKind::Freeleech => Credit { upload: 100, download: 0 },
+            ModifierKind::Neutral => Credit { upload: 0, download: 0 },
+            ModifierKind::DoubleUpload => Credit { upload: 200, download: 100 },
+        }
+    }
+}
+
+impl Modifier {
+    pub fn new(kind: ModifierKind) -> Modifier {
+        Modifier {
+            kind: kind,
+            start: None,
+            end: None,
+        }
+    }
+
+    /// Parses tables like `{ kind = "freeleech", start = 1480000000, end = 1480086400 }`.
+    pub fn from_toml(toml: &Value) -> Option<Modifier> {
+        let kind = toml.lookup("kind")
+            .map_or(None, |v| v.as_str())
+            .map_or(None, ModifierKind::parse);
+        kind.map(|kind| {
+            Modifier {
+                kind: kind,
+                start: toml.lookup("start").map_or(None, |v| v.as_integer()),
+                end: toml.lookup("end").map_or(None, |v| v.as_integer()),
+            }
+        })
+    }
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.start.is_none_or(|start| start <= now) && self.end.is_none_or(|end| now < end)
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.end.is_some_and(|end| end <= now)
+    }
+}
+
+/// The credit of all `modifiers` active at `now` combined.
+pub fn credit(modifiers: &[Modifier], now: i64) -> Credit {
+    modifiers.iter()
+        .filter(|m| m.is_active(now))
+        .fold(Credit::default(), |credit, m| credit.combine(m.kind.credit()))
+}
+
+#[test]
+fn modifier_credit() {
+    let modifiers = [Modifier {
+                         kind: ModifierKind::Freeleech,
+                         start: Some(100),
+                         end: Some(200),
+                     },
+                     Modifier::new(ModifierKind::DoubleUpload)];
+    assert!(credit(&modifiers, 50) == Credit { upload: 200, download: 100 });
+    assert!(credit(&modifiers, 100) == Credit { upload: 200, download: 0 });
+    assert!(credit(&modifiers, 200) == Credit { upload: 200, download: 100 });
+    assert!(credit(&[Modifier::new(ModifierKind::Neutral)], 0) ==
+            Credit { upload: 0, download: 0 });
+    assert!(modifiers[0].is_expired(200) && !modifiers[1].is_expired(200));
+}
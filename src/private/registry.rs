@@ -0,0 +1,107 @@
+use tracker::info_hash::InfoHash;
+use private::modifier::Modifier;
+
+use std::cmp;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TorrentStatus {
+    Approved,
+    Pending,
+    Deleted,
+}
+
+/// What the site knows about a torrent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TorrentRecord {
+    pub id: u64,
+    /// For hybrid torrents the v1 hash, which is the one their swarm is kept under.
+    pub info_hash: InfoHash,
+    /// Bytes.
+    pub size: u64,
+    pub name: String,
+    /// Freeleech and the like, see `Modifier`.
+    pub modifiers: Vec<Modifier>,
+    /// Id of the uploading user.
+    pub uploader: u64,
+    /// Seconds since the Unix epoch.
+    pub created: i64,
+    /// When the record last changed on the site, in seconds since the Unix epoch.
+    pub updated: i64,
+    pub status: TorrentStatus,
+}
+
+/// The torrents of the site by info hash. Deleted torrents are kept around, so their
+/// announces can be told apart from ones for torrents that never existed.
+#[derive(Debug, Default)]
+pub struct TorrentRegistry {
+    torrents: HashMap<InfoHash, TorrentRecord>,
+    /// The newest `updated` time seen, changes only need to be fetched from here on.
+    pub updated: i64,
+    /// Whether every torrent of the site has been loaded, announces aren't checked until then.
+    pub loaded: bool,
+}
+
+impl TorrentRegistry {
+    pub fn load(records: Vec<TorrentRecord>) -> TorrentRegistry {
+        let mut registry = TorrentRegistry::default();
+        registry.apply(records);
+        registry.loaded = true;
+        registry
+    }
+
+    /// Adds or replaces the given records, leaving all others as they are.
+    pub fn apply(&mut self, records: Vec<TorrentRecord>) {
+        for record in records {
+            self.updated = cmp::max(self.updated, record.updated);
+            self.torrents.insert(record.info_hash, record);
+        }
+    }
+
+    pub fn get(&self, hash: &InfoHash) -> Option<&TorrentRecord> {
+        self.torrents.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.torrents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.torrents.is_empty()
+    }
+
+    /// Drops modifiers which have run out.
+    pub fn expire_modifiers(&mut self, now: i64) {
+        for record in self.torrents.values_mut() {
+            record.modifiers.retain(|m| !m.is_expired(now));
+        }
+    }
+}
+
+#[test]
+fn incremental_updates() {
+    let record = |i: u8, updated: i64, status: TorrentStatus| {
+        TorrentRecord {
+            id: i as u64,
+            info_hash: InfoHash::from_bytes(&[i; 20]).unwrap(),
+            size: 1024,
+            name: format!("Torrent {}", i),
+            modifiers: Vec::new(),
+            uploader: 1,
+            created: 0,
+            updated: updated,
+            status: status,
+        }
+    };
+    let mut registry = TorrentRegistry::load(vec![record(1, 10, TorrentStatus::Approved),
+                                                  record(2, 20, TorrentStatus::Pending)]);
+    assert!(registry.len() == 2 && registry.updated == 20);
+    registry.apply(vec![record(2, 30, TorrentStatus::Approved),
+                        record(3, 25, TorrentStatus::Deleted)]);
+    assert!(registry.len() == 3 && registry.updated == 30);
+    let status = |i: u8| registry.get(&InfoHash::from_bytes(&[i; 20]).unwrap()).map(|r| r.status);
+    assert!(status(1) == Some(TorrentStatus::Approved));
+    assert!(status(2) == Some(TorrentStatus::Approved));
+    assert!(status(3) == Some(TorrentStatus::Deleted));
+    assert!(status(4).is_none());
+}
@@ -0,0 +1,65 @@
+use config::PrivateConfig;
+use tracker::peer::Delta;
+
+/// A user's credited totals, as counted from the deltas of their announces.
+#[derive(Clone, Debug, Default)]
+pub struct User {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl User {
+    pub fn add_delta(&mut self, delta: &Delta) {
+        self.uploaded = self.uploaded.saturating_add(delta.credited_upload);
+        self.downloaded = self.downloaded.saturating_add(delta.credited_download);
+    }
+
+    /// Upload over download, infinite for users who haven't downloaded anything.
+    pub fn ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            return f64::INFINITY;
+        }
+        self.uploaded as f64 / self.downloaded as f64
+    }
+
+    /// Whether the user is past the grace amount with a ratio below the minimum.
+    pub fn is_ratio_restricted(&self, config: &PrivateConfig) -> bool {
+        self.downloaded > config.ratio_grace && self.ratio() < config.min_ratio
+    }
+
+    pub fn ratio_warning(&self, config: &PrivateConfig) -> Option<String> {
+        if self.ratio() >= config.warn_ratio {
+            return None;
+        }
+        Some(if self.is_ratio_restricted(config) {
+            format!("Your ratio of {:.2} is below {:.2}, downloading is disabled until you \
+                     seed more.",
+                    self.ratio(),
+                    config.min_ratio)
+        } else {
+            format!("Your ratio of {:.2} is low, downloading will be disabled below {:.2}.",
+                    self.ratio(),
+                    config.min_ratio)
+        })
+    }
+}
+
+#[test]
+fn ratio_limits() {
+    let config = PrivateConfig {
+        min_ratio: 0.5,
+        warn_ratio: 0.7,
+        ratio_grace: 100,
+        ..Default::default()
+    };
+    let mut user = User::default();
+    assert!(!user.is_ratio_restricted(&config) && user.ratio_warning(&config).is_none());
+    user.downloaded = 100;
+    assert!(!user.is_ratio_restricted(&config) && user.ratio_warning(&config).is_some());
+    user.downloaded = 200;
+    assert!(user.is_ratio_restricted(&config));
+    user.uploaded = 120;
+    assert!(!user.is_ratio_restricted(&config) && user.ratio_warning(&config).is_some());
+    user.uploaded = 140;
+    assert!(user.ratio_warning(&config).is_none());
+}
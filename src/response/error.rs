@@ -1,4 +1,5 @@
 use response::TrackerResponse;
+use std::borrow::Cow;
 use std::num::ParseIntError;
 
 #[derive(Debug)]
@@ -7,31 +8,72 @@ pub enum ErrorResponse {
     BadRequest,
     BadAction,
     BadPeer,
+    /// A client the private tracker doesn't allow, described as name and version.
+    BadClient(String),
+    BadKey,
+    NotFound,
+    /// Leeching refused to a user whose ratio is below the required one.
+    LowRatio {
+        ratio: f64,
+        required: f64,
+    },
+    /// Leeching refused to a user with too many hit-and-runs.
+    HitAndRuns(usize),
+    /// Per user limits, of torrents leeched at once, IPs using the passkey and peers seeding
+    /// a torrent.
+    LeechLimit(usize),
+    IpLimit(usize),
+    SeedLimit(usize),
+    UnregisteredTorrent,
+    PendingTorrent,
+    DeletedTorrent,
 }
 
-impl TrackerResponse for ErrorResponse {
-    fn to_bencode(&self) -> Vec<u8> {
-        let resp = match *self {
-            ErrorResponse::BadAuth => {
-                ben_map!{
-                    "failure reason" => ben_bytes!("Improper authentication provided.")
-                }
+impl ErrorResponse {
+    pub fn reason(&self) -> Cow<'static, str> {
+        Cow::from(match *self {
+            ErrorResponse::BadAuth => "Improper authentication provided.",
+            ErrorResponse::BadRequest => "Improper request sent.",
+            ErrorResponse::BadAction => "Improper action sent.",
+            ErrorResponse::BadPeer => "Your client is not allowed.",
+            ErrorResponse::BadClient(ref client) => {
+                return Cow::from(format!("Your client ({}) is not allowed.", client));
+            }
+            ErrorResponse::BadKey => "Peer key does not match.",
+            ErrorResponse::NotFound => "Torrent not found.",
+            ErrorResponse::UnregisteredTorrent => "Torrent not registered with this tracker.",
+            ErrorResponse::PendingTorrent => "Torrent is pending approval.",
+            ErrorResponse::DeletedTorrent => "Torrent has been deleted.",
+            ErrorResponse::LowRatio { ratio, required } => {
+                return Cow::from(format!("Your ratio of {:.2} is below the required {:.2}, \
+                                          seed to be able to download again.",
+                                         ratio,
+                                         required));
             }
-            ErrorResponse::BadRequest => {
-                ben_map!{
-                    "failure reason" => ben_bytes!("Improper request sent.")
-                }
+            ErrorResponse::HitAndRuns(count) => {
+                return Cow::from(format!("You have {} hit-and-runs, seed them to be able to \
+                                          download again.",
+                                         count));
             }
-            ErrorResponse::BadAction => {
-                ben_map!{
-                    "failure reason" => ben_bytes!("Improper action sent.")
-                }
+            ErrorResponse::LeechLimit(max) => {
+                return Cow::from(format!("You can only leech {} torrents at once.", max));
             }
-            ErrorResponse::BadPeer => {
-                ben_map!{
-                    "failure reason" => ben_bytes!("Your client is not allowed.")
-                }
+            ErrorResponse::IpLimit(max) => {
+                return Cow::from(format!("Your passkey is already in use from {} IPs.", max));
             }
+            ErrorResponse::SeedLimit(max) => {
+                return Cow::from(format!("You can only seed a torrent from {} clients at once.",
+                                         max));
+            }
+        })
+    }
+}
+
+impl TrackerResponse for ErrorResponse {
+    fn to_bencode(&self) -> Vec<u8> {
+        let reason = self.reason();
+        let resp = ben_map!{
+            "failure reason" => ben_bytes!(&reason[..])
         };
         resp.encode()
     }
@@ -1,9 +1,16 @@
 use tracker::announce::AnnounceResponse;
+use tracker::peer_id::PeerId;
 use tracker::scrape::ScrapeResponse;
 use tracker::stats::StatsResponse;
+use tracker::torrent::{Sample, TorrentResponse};
+use tracker::leaderboard::{Entry, Leaderboards};
+use private::hnr::HitAndRun;
+use tracker::peer::Delta;
 
-use bip_bencode::Bencode;
-use std::collections::BTreeMap;
+use rustc_serialize::json::Json;
+
+use std::io::{Cursor, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub enum SuccessResponse {
     Announce(AnnounceResponse),
@@ -12,95 +19,391 @@ pub enum SuccessResponse {
 }
 
 impl SuccessResponse {
-    pub fn http_resp(& self) -> Vec<u8> {
+    pub fn write_http_resp(&self, buf: &mut Vec<u8>) {
         match *self {
-            SuccessResponse::Announce(ref a) => bencode_announce(a),
-            SuccessResponse::Scrape(ref s) => bencode_scrape(s),
-            SuccessResponse::Stats(ref s) => display_stats(s),
+            SuccessResponse::Announce(ref a) => bencode_announce(a, buf),
+            SuccessResponse::Scrape(ref s) => bencode_scrape(s, buf),
+            SuccessResponse::Stats(ref s) => display_stats(s, buf),
         }
     }
 }
 
-fn bencode_announce(a: &AnnounceResponse) -> Vec<u8> {
+/// Writes the announce response straight into `buf`. Dictionary keys have to be in sorted
+/// order, so the fields are written in that order rather than through a bencode tree.
+pub fn bencode_announce(a: &AnnounceResponse, buf: &mut Vec<u8>) {
     let peers = a.peers();
     let stats = a.stats();
 
+    buf.push(b'd');
+    write_int(buf, "complete", stats.complete);
+    write_int(buf, "downloaded", stats.downloaded);
+    write_int(buf, "incomplete", stats.incomplete);
+    write_int(buf, "interval", a.announce_int.as_secs() as i64);
+    write_int(buf, "min interval", a.min_announce_int.as_secs() as i64);
     if a.compact() {
-        let mut peer_bytes = Vec::with_capacity(6 * peers.peers4.len() as usize);
-        for p in peers.peers4.iter() {
-            peer_bytes.extend(p.get_ipv4_bytes().unwrap());
+        write_str(buf, "peers");
+        write_bytes(buf, &peers.peers4);
+        write_str(buf, "peers6");
+        write_bytes(buf, &peers.peers6);
+    } else {
+        write_str(buf, "peers");
+        buf.push(b'l');
+        for (entry, id) in peers.peers4.chunks(6).zip(peers.ids4.iter()) {
+            let ip = Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]);
+            write_peer(buf, id, IpAddr::V4(ip), &entry[4..]);
         }
+        // Peer dictionaries carry their address family, so IPv6 peers go in the same list.
+        for (entry, id) in peers.peers6.chunks(18).zip(peers.ids6.iter()) {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&entry[..16]);
+            write_peer(buf, id, IpAddr::V6(Ipv6Addr::from(octets)), &entry[16..]);
+        }
+        buf.push(b'e');
+    }
+    if let Some(ref warning) = a.warning {
+        write_str(buf, "warning message");
+        write_str(buf, warning);
+    }
+    buf.push(b'e');
+}
+
+/// Writes a peer dictionary from its compact entry, `port` being the big endian port bytes.
+fn write_peer(buf: &mut Vec<u8>, id: &PeerId, ip: IpAddr, port: &[u8]) {
+    // Formatted on the stack first, since the length has to go in front of the string.
+    let mut ip_buf = [0u8; 64];
+    let len = {
+        let mut cursor = Cursor::new(&mut ip_buf[..]);
+        let _ = write!(cursor, "{}", ip);
+        cursor.position() as usize
+    };
+    buf.push(b'd');
+    write_str(buf, "ip");
+    write_bytes(buf, &ip_buf[..len]);
+    write_str(buf, "peer id");
+    write_bytes(buf, id.as_bytes());
+    write_int(buf, "port", ((port[0] as i64) << 8) | port[1] as i64);
+    buf.push(b'e');
+}
 
-        let mut peer6_bytes = Vec::with_capacity(18 * peers.peers6.len() as usize);
-        for p in peers.peers6.iter() {
-            peer6_bytes.extend(p.get_ipv6_bytes().unwrap());
+fn bencode_scrape(s: &ScrapeResponse, buf: &mut Vec<u8>) {
+    let mut torrents: Vec<_> = s.torrents.iter().collect();
+    torrents.sort_by(|a, b| a.0.cmp(b.0));
+    buf.extend_from_slice(b"d5:filesd");
+    for (key, val) in torrents {
+        write_bytes(buf, key.as_bytes());
+        buf.push(b'd');
+        write_int(buf, "complete", val.complete);
+        write_int(buf, "downloaded", val.downloaded);
+        write_int(buf, "incomplete", val.incomplete);
+        buf.push(b'e');
+    }
+    buf.extend_from_slice(b"ee");
+}
+
+/// Writes a torrent's stats, using the seeder, leecher and snatch terms rather than the
+/// scrape ones since these are meant for people.
+pub fn bencode_torrent(t: &TorrentResponse, buf: &mut Vec<u8>) {
+    buf.push(b'd');
+    write_str(buf, "history");
+    buf.push(b'l');
+    for sample in t.history.iter() {
+        buf.push(b'd');
+        write_int(buf, "leechers", sample.stats.incomplete);
+        write_int(buf, "seeders", sample.stats.complete);
+        write_int(buf, "snatches", sample.stats.downloaded);
+        write_int(buf, "time", sample.time);
+        buf.push(b'e');
+    }
+    buf.push(b'e');
+    write_str(buf, "info_hash");
+    write_str(buf, &t.info_hash.to_hex());
+    write_int(buf, "leechers", t.stats.incomplete);
+    write_int(buf, "seeders", t.stats.complete);
+    write_int(buf, "snatches", t.stats.downloaded);
+    buf.push(b'e');
+}
+
+pub fn json_torrent(t: &TorrentResponse, buf: &mut Vec<u8>) {
+    let _ = write!(buf,
+                   "{{\"info_hash\":\"{}\",\"seeders\":{},\"leechers\":{},\"snatches\":{},\
+                    \"history\":[",
+                   t.info_hash,
+                   t.stats.complete,
+                   t.stats.incomplete,
+                   t.stats.downloaded);
+    for (i, sample) in t.history.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
         }
+        write_json_sample(buf, sample);
+    }
+    buf.extend_from_slice(b"]}");
+}
 
-        let benc = ben_map!{
-           "peers" => ben_bytes!(&peer_bytes),
-           "peers6" => ben_bytes!(&peer6_bytes),
-           "interval" => ben_int!(a.announce_int.as_secs() as i64),
-           "min interval" => ben_int!(a.min_announce_int.as_secs() as i64),
-           "complete" => ben_int!(stats.complete),
-           "downloaded" => ben_int!(stats.downloaded),
-           "incomplete" => ben_int!(stats.incomplete)
-        };
-        benc.encode()
-    } else {
-        let temp_peers: Vec<_> = peers.peers4.iter().map(|p| {
-            (p.id.clone(), p.get_ipv4_str().unwrap(), p.ipv4.unwrap().port())
-        }).collect();
-        let peers_benc: Vec<_> = temp_peers.iter().map(|&(ref id, ref ip, port)| {
-            ben_map!{
-                "peer id" => ben_bytes!(id),
-                "ip" => ben_bytes!(ip),
-                "port" => ben_int!(port as i64)
-            }
-        }).collect();
-
-        let temp_peers6: Vec<_> = peers.peers6.iter().map(|p| {
-            (p.id.clone(), p.get_ipv6_str().unwrap(), p.ipv6.unwrap().port())
-        }).collect();
-        let peers6_benc: Vec<_> = temp_peers6.iter().map(|&(ref id, ref ip, port)| {
-            ben_map!{
-                "peer id" => ben_bytes!(id),
-                "ip" => ben_bytes!(ip),
-                "port" => ben_int!(port as i64)
-            }
-        }).collect();
-
-        let benc = ben_map!{
-           "peers" => Bencode::List(peers_benc),
-           "peers6" => Bencode::List(peers6_benc),
-           "interval" => ben_int!(1800),
-           "min interval" => ben_int!(900),
-           "complete" => ben_int!(stats.complete),
-           "downloaded" => ben_int!(stats.downloaded),
-           "incomplete" => ben_int!(stats.incomplete)
-        };
-        benc.encode()
+fn write_json_sample(buf: &mut Vec<u8>, sample: &Sample) {
+    let _ = write!(buf,
+                   "{{\"time\":{},\"seeders\":{},\"leechers\":{},\"snatches\":{}}}",
+                   sample.time,
+                   sample.stats.complete,
+                   sample.stats.incomplete,
+                   sample.stats.downloaded);
+}
+
+pub fn bencode_leaderboards(l: &Leaderboards, buf: &mut Vec<u8>) {
+    buf.push(b'd');
+    write_board(buf, "leechers", &l.leechers);
+    write_board(buf, "seeders", &l.seeders);
+    write_board(buf, "snatches_24h", &l.snatches_24h);
+    write_int(buf, "updated", l.updated);
+    write_board(buf, "worst_ratio", &l.worst_ratio);
+    buf.push(b'e');
+}
+
+pub fn bencode_needs_seeders(l: &Leaderboards, buf: &mut Vec<u8>) {
+    buf.push(b'd');
+    write_board(buf, "torrents", &l.needs_seeders);
+    write_int(buf, "updated", l.updated);
+    buf.push(b'e');
+}
+
+fn write_board(buf: &mut Vec<u8>, key: &str, entries: &[Entry]) {
+    write_str(buf, key);
+    buf.push(b'l');
+    for e in entries {
+        buf.push(b'd');
+        write_str(buf, "info_hash");
+        write_str(buf, &e.info_hash.to_hex());
+        write_int(buf, "leechers", e.leechers as i64);
+        write_int(buf, "seeders", e.seeders as i64);
+        write_int(buf, "snatches", e.snatches as i64);
+        write_int(buf, "snatches_24h", e.snatches_24h as i64);
+        buf.push(b'e');
     }
+    buf.push(b'e');
 }
 
-fn bencode_scrape(s: &ScrapeResponse) -> Vec<u8> {
-    let mut resp = BTreeMap::new();
-    let mut torrents = BTreeMap::new();
-    for (key, val) in s.torrents.iter() {
-        let torrent = ben_map!{
-                "complete" => ben_int!(val.complete),
-                "downloaded" => ben_int!(val.downloaded),
-                "incomplete" => ben_int!(val.incomplete)
-            };
-        torrents.insert(AsRef::as_ref(key), torrent);
+pub fn json_leaderboards(l: &Leaderboards, buf: &mut Vec<u8>) {
+    let _ = write!(buf, "{{\"updated\":{}", l.updated);
+    write_json_board(buf, "seeders", &l.seeders);
+    write_json_board(buf, "leechers", &l.leechers);
+    write_json_board(buf, "snatches_24h", &l.snatches_24h);
+    write_json_board(buf, "worst_ratio", &l.worst_ratio);
+    buf.push(b'}');
+}
+
+pub fn json_needs_seeders(l: &Leaderboards, buf: &mut Vec<u8>) {
+    let _ = write!(buf, "{{\"updated\":{}", l.updated);
+    write_json_board(buf, "torrents", &l.needs_seeders);
+    buf.push(b'}');
+}
+
+fn write_json_board(buf: &mut Vec<u8>, key: &str, entries: &[Entry]) {
+    let _ = write!(buf, ",\"{}\":[", key);
+    for (i, e) in entries.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        let _ = write!(buf,
+                       "{{\"info_hash\":\"{}\",\"seeders\":{},\"leechers\":{},\"snatches\":{},\
+                        \"snatches_24h\":{}}}",
+                       e.info_hash,
+                       e.seeders,
+                       e.leechers,
+                       e.snatches,
+                       e.snatches_24h);
     }
-    resp.insert("files", Bencode::Dict(torrents));
-    Bencode::Dict(resp).encode()
-}
-fn display_stats(s: &StatsResponse) -> Vec<u8> {
-    String::from(format!("Announces/s: {}\nScrapes/s: {}\nTorrents: {}\nPeers: {}",
-                         s.announce_rate,
-                         s.scrape_rate,
-                         s.torrents,
-                         s.peers))
-        .into_bytes()
+    buf.push(b']');
+}
+
+pub fn bencode_hit_and_runs(hnrs: &[HitAndRun], buf: &mut Vec<u8>) {
+    buf.push(b'd');
+    write_str(buf, "hit_and_runs");
+    buf.push(b'l');
+    for h in hnrs {
+        buf.push(b'd');
+        write_int(buf, "completed", h.record.completed.unwrap_or(0));
+        write_int(buf, "downloaded", h.record.downloaded as i64);
+        write_str(buf, "info_hash");
+        write_str(buf, &h.info_hash.to_hex());
+        write_int(buf, "last_seen", h.record.last_seen);
+        write_str(buf, "passkey");
+        write_str(buf, &h.passkey);
+        write_int(buf, "seed_time", h.record.seed_time as i64);
+        write_int(buf, "uploaded", h.record.uploaded as i64);
+        buf.push(b'e');
+    }
+    buf.extend_from_slice(b"ee");
+}
+
+pub fn json_hit_and_runs(hnrs: &[HitAndRun], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"{\"hit_and_runs\":[");
+    for (i, h) in hnrs.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        let _ = write!(buf,
+                       "{{\"passkey\":{},\"info_hash\":\"{}\",\"completed\":{},\
+                        \"seed_time\":{},\"uploaded\":{},\"downloaded\":{},\
+                        \"last_seen\":{}}}",
+                       Json::String(h.passkey.clone()),
+                       h.info_hash,
+                       h.record.completed.unwrap_or(0),
+                       h.record.seed_time,
+                       h.record.uploaded,
+                       h.record.downloaded,
+                       h.record.last_seen);
+    }
+    buf.extend_from_slice(b"]}");
+}
+
+pub fn bencode_quarantine(deltas: &[Delta], buf: &mut Vec<u8>) {
+    buf.push(b'd');
+    write_str(buf, "quarantine");
+    buf.push(b'l');
+    for d in deltas {
+        buf.push(b'd');
+        write_int(buf, "download", d.download as i64);
+        write_str(buf, "info_hash");
+        write_str(buf, &d.info_hash.to_hex());
+        write_str(buf, "passkey");
+        write_str(buf, d.passkey.as_ref().map_or("", |p| &p[..]));
+        write_str(buf, "peer_id");
+        write_str(buf, &d.peer_id.to_hex());
+        write_str(buf, "suspicions");
+        buf.push(b'l');
+        for s in &d.suspicions {
+            write_str(buf, s.name());
+        }
+        buf.push(b'e');
+        write_int(buf, "upload", d.upload as i64);
+        buf.push(b'e');
+    }
+    buf.extend_from_slice(b"ee");
+}
+
+pub fn json_quarantine(deltas: &[Delta], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"{\"quarantine\":[");
+    for (i, d) in deltas.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        let suspicions: Vec<_> = d.suspicions
+            .iter()
+            .map(|s| Json::String(String::from(s.name())))
+            .collect();
+        let _ = write!(buf,
+                       "{{\"passkey\":{},\"info_hash\":\"{}\",\"peer_id\":\"{}\",\
+                        \"upload\":{},\"download\":{},\"suspicions\":{}}}",
+                       Json::String(d.passkey.clone().unwrap_or_default()),
+                       d.info_hash,
+                       d.peer_id.to_hex(),
+                       d.upload,
+                       d.download,
+                       Json::Array(suspicions));
+    }
+    buf.extend_from_slice(b"]}");
+}
+
+fn display_stats(s: &StatsResponse, buf: &mut Vec<u8>) {
+    let _ = write!(buf,
+                   "Announces/s: {:.2} (1m), {:.2} (5m)\nScrapes/s: {:.2} (1m), {:.2} (5m)\n\
+                    Torrents: {}\nPeers: {}\nSeeders: {}\nLeechers: {}\n\
+                    Announces: {} (started: {}, completed: {}, stopped: {})\nScrapes: {}\n\
+                    Uploaded: {}\nDownloaded: {}\nUptime: {}s",
+                   s.announce_rate,
+                   s.announce_rate_5m,
+                   s.scrape_rate,
+                   s.scrape_rate_5m,
+                   s.torrents,
+                   s.peers,
+                   s.seeders,
+                   s.leechers,
+                   s.announces,
+                   s.started,
+                   s.completed,
+                   s.stopped,
+                   s.scrapes,
+                   s.uploaded,
+                   s.downloaded,
+                   s.uptime);
+    if cfg!(feature = "private") {
+        let _ = write!(buf,
+                       "\nFlush queue: {} deltas, {} rows ({} journaled)",
+                       s.queued_deltas,
+                       s.queued_rows,
+                       s.journaled_rows);
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let _ = write!(buf, "{}:", bytes.len());
+    buf.extend_from_slice(bytes);
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_bytes(buf, s.as_bytes());
+}
+
+fn write_int(buf: &mut Vec<u8>, key: &str, val: i64) {
+    write_str(buf, key);
+    let _ = write!(buf, "i{}e", val);
+}
+
+#[test]
+fn compact_announce_encoding() {
+    use std::time::Duration;
+
+    let mut resp = AnnounceResponse::default();
+    resp.reset(true);
+    resp.announce_int = Duration::from_secs(1800);
+    resp.min_announce_int = Duration::from_secs(900);
+    resp.peers_mut().peers4.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+    let mut buf = Vec::new();
+    bencode_announce(&resp, &mut buf);
+    assert!(buf[..] ==
+            b"d8:completei0e10:downloadedi0e10:incompletei0e8:intervali1800e\
+               12:min intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers60:e"[..]);
+
+    resp.reset(false);
+    resp.peers_mut().peers4.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+    resp.peers_mut().ids4.push(PeerId::from_bytes(b"-TR2940-000000000000").unwrap());
+    resp.peers_mut().peers6.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+                                                 0x1a, 0xe1]);
+    resp.peers_mut().ids6.push(PeerId::from_bytes(b"-TR2940-000000000001").unwrap());
+    buf.clear();
+    bencode_announce(&resp, &mut buf);
+    assert!(buf[..] ==
+            b"d8:completei0e10:downloadedi0e10:incompletei0e8:intervali1800e\
+               12:min intervali900e5:peersld2:ip8:10.0.0.17:peer id\
+               20:-TR2940-0000000000004:porti6881eed2:ip3:::17:peer id\
+               20:-TR2940-0000000000014:porti6881eeee"[..]);
+}
+
+#[test]
+fn torrent_encoding() {
+    use tracker::info_hash::InfoHash;
+    use tracker::torrent::Stats;
+
+    let stats = Stats {
+        complete: 2,
+        incomplete: 1,
+        downloaded: 5,
+    };
+    let torrent = TorrentResponse {
+        info_hash: InfoHash::from_bytes(&[0xab; 20]).unwrap(),
+        stats: stats,
+        history: vec![Sample { time: 1500000000, stats: stats }],
+    };
+    let mut buf = Vec::new();
+    bencode_torrent(&torrent, &mut buf);
+    assert!(buf[..] ==
+            b"d7:historyld8:leechersi1e7:seedersi2e8:snatchesi5e4:timei1500000000eee\
+               9:info_hash40:abababababababababababababababababababab\
+               8:leechersi1e7:seedersi2e8:snatchesi5ee"[..]);
+    buf.clear();
+    json_torrent(&torrent, &mut buf);
+    assert!(buf[..] ==
+            b"{\"info_hash\":\"abababababababababababababababababababab\",\"seeders\":2,\
+               \"leechers\":1,\"snatches\":5,\"history\":[{\"time\":1500000000,\
+               \"seeders\":2,\"leechers\":1,\"snatches\":5}]}"[..]);
 }
@@ -2,11 +2,14 @@ use std::net::{SocketAddrV4, SocketAddrV6};
 use std::time::Duration;
 
 use tracker::torrent::{Stats, Peers};
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
 
 pub struct Announce {
-    pub info_hash: String,
-    pub peer_id: String,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
     pub passkey: Option<String>,
+    pub key: Option<String>,
     pub ipv4: Option<SocketAddrV4>,
     pub ipv6: Option<SocketAddrV6>,
     pub ul: u64,
@@ -25,85 +28,79 @@ pub enum Action {
     Stopped,
 }
 
-pub struct AnnouncePeer {
-    pub id: String,
-    pub ipv4: Option<SocketAddrV4>,
-    pub ipv6: Option<SocketAddrV6>,
-}
-
-pub struct AnnounceResponse {
-    peers: Peers,
-    stats: Stats,
-    compact: bool,
-    pub announce_int: Duration,
-    pub min_announce_int: Duration
-}
-
-impl AnnouncePeer {
-    pub fn get_ipv4_bytes(&self) -> Option<Vec<u8>> {
-        match self.ipv4 {
-            None => None,
-            Some(sock) => {
-                let mut v = Vec::with_capacity(6);
-                v.extend(sock.ip().octets().to_vec());
-                v.extend(u16_to_u8(sock.port()));
-                Some(v)
-            }
-        }
-    }
-
-    pub fn get_ipv4_str(&self) -> Option<String> {
-        match self.ipv4 {
-            None => None,
-            Some(sock) => Some(format!("{}", sock.ip())),
-        }
-    }
+/// A compact announce of peer `[peer; 20]` at 10.0.0.`peer` leeching torrent `[hash; 20]`,
+/// for tests to adjust with struct update syntax.
+#[cfg(test)]
+pub fn test_announce(hash: u8, peer: u8) -> Announce {
+    use std::net::Ipv4Addr;
 
-    pub fn get_ipv6_bytes(&self) -> Option<Vec<u8>> {
-        match self.ipv6 {
-            None => None,
-            Some(sock) => {
-                let mut v = Vec::with_capacity(18);
-                for seg in sock.ip().segments().iter() {
-                    v.extend(u16_to_u8(seg.clone()));
-                }
-                v.extend(u16_to_u8(sock.port()));
-                Some(v)
-            }
-        }
+    Announce {
+        info_hash: InfoHash::from_bytes(&[hash; 20]).unwrap(),
+        peer_id: PeerId::from_bytes(&[peer; 20]).unwrap(),
+        passkey: None,
+        key: None,
+        ipv4: Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, peer), 6881)),
+        ipv6: None,
+        ul: 0,
+        dl: 0,
+        left: 1,
+        action: Action::Leeching,
+        numwant: 25,
+        compact: true,
     }
+}
 
-    pub fn get_ipv6_str(&self) -> Option<String> {
-        match self.ipv6 {
-            None => None,
-            Some(sock) => Some(format!("{}", sock.ip())),
+impl Action {
+    pub fn from_event(event: Option<&str>, left: u64) -> Action {
+        match event {
+            Some("stopped") => Action::Stopped,
+            Some("completed") => Action::Completed,
+            _ if left == 0 => Action::Seeding,
+            _ => Action::Leeching,
         }
     }
 }
 
-fn u16_to_u8(i: u16) -> Vec<u8> {
-    vec![(i >> 8) as u8, (i & 0xff) as u8]
+/// The result of an announce. Responses are meant to be reused across announces, so that
+/// the peer lists don't need to be allocated every time.
+#[derive(Default)]
+pub struct AnnounceResponse {
+    peers: Peers,
+    stats: Stats,
+    compact: bool,
+    pub announce_int: Duration,
+    pub min_announce_int: Duration,
+    pub warning: Option<String>,
 }
 
 impl AnnounceResponse {
-    pub fn new(peers: Peers, stats: Stats, compact: bool, announce_int: Duration, min_announce_int: Duration) -> AnnounceResponse {
-        AnnounceResponse {
-            peers: peers,
-            stats: stats,
-            compact: compact,
-            announce_int: announce_int,
-            min_announce_int: min_announce_int,
-        }
+    /// Empties the response for reuse, keeping the capacity of the peer lists.
+    pub fn reset(&mut self, compact: bool) {
+        self.peers.peers4.clear();
+        self.peers.peers6.clear();
+        self.peers.ids4.clear();
+        self.peers.ids6.clear();
+        self.stats = Stats::default();
+        self.compact = compact;
+        self.warning = None;
     }
 
     pub fn peers(&self) -> &Peers {
         &self.peers
     }
 
+    pub fn peers_mut(&mut self) -> &mut Peers {
+        &mut self.peers
+    }
+
     pub fn stats(&self) -> &Stats {
         &self.stats
     }
 
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
     pub fn compact(&self) -> bool {
         self.compact
     }
@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// A torrent's info hash as used for swarm lookups. BitTorrent v2 info hashes are SHA-256,
+/// but as per BEP 52 trackers only ever see them truncated to the first 20 bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash([u8; 20]);
+
+impl InfoHash {
+    /// Accepts either a v1 SHA-1 hash or a v2 SHA-256 hash, truncating the latter.
+    pub fn from_bytes(bytes: &[u8]) -> Option<InfoHash> {
+        match bytes.len() {
+            20 | 32 => {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&bytes[..20]);
+                Some(InfoHash(hash))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_hex(hex: &str) -> Option<InfoHash> {
+        if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(hex.len() / 2);
+        for i in 0..hex.len() / 2 {
+            match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return None,
+            }
+        }
+        InfoHash::from_bytes(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        let mut s = String::with_capacity(40);
+        for b in self.0.iter() {
+            s.push_str(&format!("{:02x}", b));
+        }
+        s
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+#[test]
+fn v2_hashes_are_truncated() {
+    let v2 = "a3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    let hash = InfoHash::from_hex(v2).unwrap();
+    assert!(hash.to_hex() == v2[..40]);
+    assert!(InfoHash::from_hex(&v2[..40]) == Some(hash));
+    assert!(InfoHash::from_bytes(&[0; 16]).is_none());
+}
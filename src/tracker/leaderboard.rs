@@ -0,0 +1,148 @@
+use tracker::info_hash::InfoHash;
+use config::TrackerConfig;
+
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+
+/// A torrent's counts as ranked on the leaderboards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+    pub info_hash: InfoHash,
+    pub seeders: u64,
+    pub leechers: u64,
+    pub snatches: u64,
+    pub snatches_24h: u64,
+}
+
+/// Ranked views over all torrents, as of the last reap. Every list is sorted best first.
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboards {
+    pub seeders: Vec<Entry>,
+    pub leechers: Vec<Entry>,
+    pub snatches_24h: Vec<Entry>,
+    /// Torrents with the most leechers per seeder.
+    pub worst_ratio: Vec<Entry>,
+    /// Torrents with leechers but at most `needs_seeders_max_seeders` seeders, most leechers
+    /// first. Meant for seedboxes looking for something to seed.
+    pub needs_seeders: Vec<Entry>,
+    /// Seconds since the Unix epoch.
+    pub updated: i64,
+}
+
+/// Collects the leaderboards over a reap, only ever holding `size` entries per list.
+pub struct LeaderboardBuilder {
+    seeders: Top,
+    leechers: Top,
+    snatches_24h: Top,
+    worst_ratio: Top,
+    needs_seeders: Top,
+    max_seeders: u64,
+    min_leechers: u64,
+}
+
+/// The `size` entries with the highest keys seen so far, lowest first in the heap so it's
+/// cheap to find the one to drop.
+struct Top {
+    size: usize,
+    heap: BinaryHeap<Reverse<(u64, Entry)>>,
+}
+
+impl LeaderboardBuilder {
+    pub fn new(config: &TrackerConfig) -> LeaderboardBuilder {
+        let size = config.leaderboard_size;
+        LeaderboardBuilder {
+            seeders: Top::new(size),
+            leechers: Top::new(size),
+            snatches_24h: Top::new(size),
+            worst_ratio: Top::new(size),
+            needs_seeders: Top::new(config.needs_seeders_size),
+            max_seeders: config.needs_seeders_max_seeders,
+            min_leechers: cmp::max(config.needs_seeders_min_leechers, 1),
+        }
+    }
+
+    pub fn add(&mut self, entry: Entry) {
+        if entry.seeders > 0 {
+            self.seeders.push(entry.seeders, entry);
+        }
+        if entry.leechers > 0 {
+            self.leechers.push(entry.leechers, entry);
+            // Counting one seeder more keeps unseeded torrents apart by their leechers.
+            self.worst_ratio.push(entry.leechers * 1000 / (entry.seeders + 1), entry);
+        }
+        if entry.snatches_24h > 0 {
+            self.snatches_24h.push(entry.snatches_24h, entry);
+        }
+        if entry.seeders <= self.max_seeders && entry.leechers >= self.min_leechers {
+            self.needs_seeders.push(entry.leechers, entry);
+        }
+    }
+
+    pub fn build(self, updated: i64) -> Leaderboards {
+        Leaderboards {
+            seeders: self.seeders.into_vec(),
+            leechers: self.leechers.into_vec(),
+            snatches_24h: self.snatches_24h.into_vec(),
+            worst_ratio: self.worst_ratio.into_vec(),
+            needs_seeders: self.needs_seeders.into_vec(),
+            updated: updated,
+        }
+    }
+}
+
+impl Top {
+    fn new(size: usize) -> Top {
+        Top {
+            size: size,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn push(&mut self, key: u64, entry: Entry) {
+        if self.heap.len() < self.size {
+            self.heap.push(Reverse((key, entry)));
+        } else if self.heap.peek().is_some_and(|lowest| (key, entry) > lowest.0) {
+            self.heap.pop();
+            self.heap.push(Reverse((key, entry)));
+        }
+    }
+
+    fn into_vec(self) -> Vec<Entry> {
+        // Sorting the reversed entries ascending puts the highest keys first.
+        self.heap.into_sorted_vec().into_iter().map(|Reverse((_, entry))| entry).collect()
+    }
+}
+
+#[test]
+fn leaderboards_keep_the_top_entries() {
+    let entry = |i: u8, seeders: u64, leechers: u64| {
+        Entry {
+            info_hash: InfoHash::from_bytes(&[i; 20]).unwrap(),
+            seeders: seeders,
+            leechers: leechers,
+            snatches: 0,
+            snatches_24h: i as u64,
+        }
+    };
+    let config = TrackerConfig {
+        leaderboard_size: 2,
+        needs_seeders_max_seeders: 10,
+        needs_seeders_min_leechers: 2,
+        ..Default::default()
+    };
+    let mut builder = LeaderboardBuilder::new(&config);
+    builder.add(entry(1, 10, 1));
+    builder.add(entry(2, 0, 5));
+    builder.add(entry(3, 30, 3));
+    builder.add(entry(4, 20, 0));
+    let boards = builder.build(0);
+
+    let ranked = |list: &[Entry]| {
+        list.iter().map(|e| e.info_hash.as_bytes()[0]).collect::<Vec<_>>()
+    };
+    assert!(ranked(&boards.seeders) == vec![3, 4]);
+    assert!(ranked(&boards.leechers) == vec![2, 3]);
+    assert!(ranked(&boards.snatches_24h) == vec![4, 3]);
+    assert!(ranked(&boards.worst_ratio) == vec![2, 3]);
+    assert!(ranked(&boards.needs_seeders) == vec![2]);
+}
@@ -3,25 +3,42 @@ pub mod peer;
 pub mod scrape;
 pub mod announce;
 pub mod stats;
+pub mod info_hash;
+pub mod peer_id;
+pub mod peer_list;
+pub mod leaderboard;
 
-use self::torrent::Torrent;
-use self::announce::{AnnounceResponse, Announce};
+use self::torrent::{Torrent, TorrentResponse};
+use self::announce::{Action, AnnounceResponse, Announce};
 use self::scrape::{ScrapeResponse, Scrape};
-use self::stats::{Stats, StatsResponse};
+use self::stats::{Event, Stats, StatsResponse};
+use self::info_hash::InfoHash;
+use self::peer_id::PeerId;
+use self::peer::Credit;
+use self::leaderboard::{LeaderboardBuilder, Leaderboards};
+use events::{EventBus, EventKind};
 use response::error::ErrorResponse;
 use response::success::SuccessResponse;
 use private::PrivateTracker;
 use config::{TrackerConfig, PrivateConfig};
 
 use std::sync::Arc;
-use parking_lot::Mutex;
-use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
 use std::thread;
-use time::SteadyTime;
+use time::{self, SteadyTime};
 
 pub struct Tracker {
-    pub torrents: Mutex<HashMap<String, Torrent>>,
+    pub torrents: Mutex<HashMap<InfoHash, Torrent>>,
+    /// Maps the truncated v2 hash of a hybrid torrent to its v1 hash, so both share a swarm.
+    pub hybrids: RwLock<HashMap<InfoHash, InfoHash>>,
     pub stats: Mutex<Stats>,
+    pub events: EventBus,
+    /// Rankings of the torrents as of the last reap.
+    pub leaderboards: RwLock<Leaderboards>,
+    /// Torrents in the order the reaper visits them.
+    reap_queue: Mutex<VecDeque<InfoHash>>,
     pub private: PrivateTracker,
     pub config: TrackerConfig,
 }
@@ -34,14 +51,37 @@ impl Default for Tracker {
 
 impl Tracker {
     pub fn new(config: TrackerConfig, pconfig: PrivateConfig) -> Tracker {
-        let torrents: Mutex<HashMap<String, Torrent>> = Mutex::new(Default::default());
+        let torrents: Mutex<HashMap<InfoHash, Torrent>> = Mutex::new(Default::default());
+        let hybrids = RwLock::new(Default::default());
         let stats = Mutex::new(Stats::new());
         let private = PrivateTracker::new(pconfig);
-        Tracker {
+        let tracker = Tracker {
             torrents: torrents,
+            hybrids: hybrids,
             stats: stats,
+            events: EventBus::new(),
+            leaderboards: RwLock::new(Default::default()),
+            reap_queue: Mutex::new(VecDeque::new()),
             private: private,
             config: config,
+        };
+        for &(v1, v2) in tracker.config.hybrid_torrents.iter() {
+            tracker.register_hybrid(v1, v2);
+        }
+        tracker
+    }
+
+    /// Registers a v1/v2 hash pair of a hybrid torrent. Announces and scrapes for the v2 hash
+    /// will be served from the v1 swarm from here on.
+    pub fn register_hybrid(&self, v1: InfoHash, v2: InfoHash) {
+        self.hybrids.write().insert(v2, v1);
+    }
+
+    /// The hash of the swarm a torrent's peers are in, which differs for hybrid torrents.
+    pub fn swarm_hash(&self, hash: &InfoHash) -> InfoHash {
+        match self.hybrids.read().get(hash) {
+            Some(v1) => *v1,
+            None => *hash,
         }
     }
 
@@ -59,6 +99,8 @@ impl Tracker {
             let tracker_priv_flush = tracker.clone();
             thread::spawn(move || {
                 info!("Starting delta flusher!");
+                // Flushing right away writes out whatever was journaled before a restart.
+                tracker_priv_flush.private.flush();
                 loop {
                     thread::sleep(tracker_priv_flush.private.config.flush_interval);
                     tracker_priv_flush.private.flush();
@@ -76,106 +118,315 @@ impl Tracker {
     }
 
     pub fn handle_announce(&self, announce: Announce) -> Result<SuccessResponse, ErrorResponse> {
+        let mut resp = AnnounceResponse::default();
+        try!(self.announce(&announce, &mut resp));
+        Ok(SuccessResponse::Announce(resp))
+    }
+
+    /// Processes an announce, writing the peers and stats into `resp`. Callers keeping `resp`
+    /// around between announces avoid allocating the peer lists every time.
+    pub fn announce(&self,
+                    announce: &Announce,
+                    resp: &mut AnnounceResponse)
+                    -> Result<(), ErrorResponse> {
+        let hash = self.swarm_hash(&announce.info_hash);
+        resp.reset(announce.compact);
+        resp.announce_int = self.config.announce_interval;
+        resp.min_announce_int = self.config.min_announce_interval;
+        let credit = if cfg!(feature = "private") {
+            self.private.credit(&hash, time::get_time().sec)
+        } else {
+            Credit::default()
+        };
         let mut torrents = self.torrents.lock();
         let mut tracker_stats = self.stats.lock();
-        let torrent = if torrents.contains_key(&announce.info_hash) {
-            let t = torrents.get_mut(&announce.info_hash).unwrap();
-            tracker_stats.announces += 1;
-            tracker_stats.peers -= t.get_peer_count();
-            let delta = t.update(&announce);
-            if cfg!(feature = "private") {
-                self.private.add_announce(delta);
+        let torrent = match torrents.entry(hash) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                if let Action::Stopped = announce.action {
+                    // There's nothing to stop, so don't bring up a swarm just for this.
+                    tracker_stats.record_announce(Event::Stopped, 0, 0);
+                    return Ok(());
+                }
+                tracker_stats.torrents += 1;
+                self.reap_queue.lock().push_back(hash);
+                self.events.emit(hash, EventKind::TorrentCreated);
+                e.insert(Torrent::new(hash))
             }
-            tracker_stats.peers += t.get_peer_count();
-            t
-        } else {
-            tracker_stats.torrents += 1;
-            tracker_stats.announces += 1;
-            tracker_stats.peers += 1;
-
-            let t = Torrent::new(announce.info_hash.clone());
-            torrents.insert(announce.info_hash.clone(), t);
-            let t = torrents.get_mut(&announce.info_hash).unwrap();
-            let delta = t.update(&announce);
-            if cfg!(feature = "private") {
-                self.private.add_announce(delta);
+        };
+        let before = torrent.get_stats();
+        let mut delta = try!(torrent.update(announce, credit));
+        let after = torrent.get_stats();
+        let event = match announce.action {
+            Action::Completed => Event::Completed,
+            Action::Stopped => Event::Stopped,
+            _ if after.complete + after.incomplete > before.complete + before.incomplete => {
+                Event::Started
             }
-            t
+            _ => Event::Update,
         };
-        let peers = torrent.get_peers(announce.numwant, announce.action);
-        let stats = torrent.get_stats();
-        Ok(SuccessResponse::Announce(AnnounceResponse::new(peers,
-                                                           stats,
-                                                           announce.compact,
-                                                           self.config.announce_interval,
-                                                           self.config.min_announce_interval)))
+        tracker_stats.record_announce(event, delta.upload, delta.download);
+        if self.events.is_enabled() {
+            let peer_id = announce.peer_id;
+            match event {
+                Event::Started => self.events.emit(hash, EventKind::PeerStarted(peer_id)),
+                Event::Completed => {
+                    self.events.emit(hash, EventKind::PeerCompleted(peer_id));
+                    if let Some(ref snatch) = delta.snatch {
+                        self.events.emit(hash,
+                                         EventKind::Snatch {
+                                             peer_id: peer_id,
+                                             passkey: snatch.passkey.clone(),
+                                             ip: snatch.ip,
+                                             snatches: after.downloaded,
+                                         });
+                    }
+                }
+                Event::Stopped if after.complete + after.incomplete <
+                                  before.complete + before.incomplete => {
+                    self.events.emit(hash, EventKind::PeerStopped(peer_id));
+                }
+                _ => {}
+            }
+        }
+        tracker_stats.add_peers(after.complete as u64, after.incomplete as u64);
+        tracker_stats.remove_peers(before.complete as u64, before.incomplete as u64);
+        if cfg!(feature = "private") {
+            // Everything private is kept by swarm, so both hashes of hybrids count the same.
+            delta.info_hash = hash;
+            self.private.check_delta(&mut delta);
+            if delta.is_suspicious() {
+                self.events.emit(hash,
+                                 EventKind::Suspicious {
+                                     peer_id: announce.peer_id,
+                                     passkey: delta.passkey.clone(),
+                                     upload: delta.upload,
+                                     download: delta.download,
+                                     suspicions: delta.suspicions.clone(),
+                                 });
+            }
+            self.private.add_announce(delta);
+            self.private.index_peer(&hash, announce);
+        }
+        torrent.get_peers(&announce.peer_id,
+                          announce.numwant,
+                          announce.action.clone(),
+                          !announce.compact,
+                          resp.peers_mut());
+        resp.set_stats(after);
+        Ok(())
     }
 
     pub fn handle_scrape(&self, scrape: Scrape) -> Result<SuccessResponse, ErrorResponse> {
         let mut torrents = HashMap::new();
         for hash in scrape.torrents {
-            match self.torrents.lock().get(&hash) {
-                Some(ref t) => {
-                    let stats = t.get_stats();
-                    torrents.insert(hash.clone(), stats);
-                }
-                None => {}
-            };
+            if let Some(t) = self.torrents.lock().get(&self.swarm_hash(&hash)) {
+                let stats = t.get_stats();
+                torrents.insert(hash, stats);
+            }
         }
 
-        let mut tracker_stats = self.stats.lock();
-        tracker_stats.scrapes += 1;
+        self.stats.lock().record_scrape();
 
         Ok(SuccessResponse::Scrape(ScrapeResponse { torrents: torrents }))
     }
 
+    /// Picks up to `amount` peers of a swarm which are only reachable through WebRTC.
+    pub fn get_webrtc_peers(&self, hash: &InfoHash, amount: usize, exclude: &PeerId) -> Vec<PeerId> {
+        match self.torrents.lock().get(&self.swarm_hash(hash)) {
+            Some(t) => t.get_webrtc_peers(amount, exclude),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_torrent(&self, hash: &InfoHash) -> Result<TorrentResponse, ErrorResponse> {
+        match self.torrents.lock().get(&self.swarm_hash(hash)) {
+            Some(t) => {
+                Ok(TorrentResponse {
+                    info_hash: *hash,
+                    stats: t.get_stats(),
+                    history: t.history().iter().cloned().collect(),
+                })
+            }
+            None => Err(ErrorResponse::NotFound),
+        }
+    }
+
+    pub fn get_leaderboards(&self) -> Leaderboards {
+        self.leaderboards.read().clone()
+    }
+
     pub fn get_stats(&self) -> Result<SuccessResponse, ErrorResponse> {
-        let ref stats = *self.stats.lock();
-        let resp = StatsResponse::new(stats);
+        let mut resp = StatsResponse::new(&self.stats.lock());
+        if cfg!(feature = "private") {
+            let (deltas, rows, journaled) = self.private.queue_depth();
+            resp.queued_deltas = deltas as u64;
+            resp.queued_rows = rows as u64;
+            resp.journaled_rows = journaled as u64;
+        }
         Ok(SuccessResponse::Stats(resp))
     }
 
+    /// Goes over every torrent once, dropping stale torrents and peers. The torrents are
+    /// visited in batches of about `reap_batch_size` peers, releasing the lock in between so
+    /// announces never wait on more than one batch. The leaderboards are rebuilt on the way.
     pub fn reap(&self) {
-        // Clear stats
-        let mut stats = self.stats.lock();
-        stats.update();
-        // Delete torrents which are too old, and reap peers for the others.
-        let to_del: Vec<_> = self.torrents.lock()
-                                 .iter()
-                                 .filter_map(|(k, torrent)| {
-                                     if SteadyTime::now() - torrent.last_action >
-                                        self.config.min_torrent_update_interval {
-                                         Some(k.clone())
-                                     } else {
-                                         None
-                                     }
-                                 })
-                                 .collect();
-        for torrent in to_del {
-            stats.torrents -= 1;
-            self.torrents.lock().remove(&torrent);
-        }
-
-        let to_reap: Vec<_> = self.torrents.lock()
-                                  .iter()
-                                  .filter_map(|(k, torrent)| {
-                                      if SteadyTime::now() - torrent.last_action >
-                                         self.config.min_torrent_update_interval {
-                                          None
-                                      } else {
-                                          Some(k.clone())
-                                      }
-                                  })
-                                  .collect();
-        stats.peers = 0;
-        for info_hash in to_reap {
-            match self.torrents.lock().get_mut(&info_hash) {
-                Some(ref mut t) => {
-                    t.reap(&self.config.min_peer_update_interval);
-                    stats.peers += t.get_peer_count();
+        let mut boards = LeaderboardBuilder::new(&self.config);
+        let mut remaining = self.reap_queue.lock().len();
+        while remaining > 0 {
+            let mut reaped_torrents = 0;
+            let mut reaped_seeders = 0;
+            let mut reaped_leechers = 0;
+            {
+                let now = SteadyTime::now();
+                let time = time::get_time().sec;
+                let mut torrents = self.torrents.lock();
+                let mut budget = self.config.reap_batch_size;
+                while remaining > 0 && budget > 0 {
+                    remaining -= 1;
+                    let hash = match self.reap_queue.lock().pop_front() {
+                        Some(hash) => hash,
+                        None => break,
+                    };
+                    let keep = match torrents.get_mut(&hash) {
+                        Some(t) => {
+                            let before = t.get_stats();
+                            budget = budget.saturating_sub(t.get_peer_count() as usize + 1);
+                            let keep = now - t.last_action <=
+                                       self.config.min_torrent_update_interval;
+                            if keep {
+                                let reaped = t.reap(now, &self.config.min_peer_update_interval);
+                                if cfg!(feature = "private") {
+                                    self.private.remove_peers(&hash, &reaped);
+                                }
+                                t.record_history(time, self.config.torrent_history);
+                                t.forget_completions(time);
+                                let stats = t.get_stats();
+                                boards.add(leaderboard::Entry {
+                                    info_hash: hash,
+                                    seeders: stats.complete as u64,
+                                    leechers: stats.incomplete as u64,
+                                    snatches: stats.downloaded as u64,
+                                    snatches_24h: t.snatches_24h(time),
+                                });
+                            }
+                            let after = if keep { t.get_stats() } else { Default::default() };
+                            reaped_seeders += (before.complete - after.complete) as u64;
+                            reaped_leechers += (before.incomplete - after.incomplete) as u64;
+                            keep
+                        }
+                        None => continue,
+                    };
+                    if keep {
+                        self.reap_queue.lock().push_back(hash);
+                    } else {
+                        let removed = torrents.remove(&hash);
+                        if cfg!(feature = "private") {
+                            let peers = removed.map_or(Vec::new(), Torrent::into_peers);
+                            self.private.remove_peers(&hash, &peers);
+                        }
+                        reaped_torrents += 1;
+                        self.events.emit(hash, EventKind::TorrentReaped);
+                    }
                 }
-                None => {}
             }
+            {
+                let mut stats = self.stats.lock();
+                stats.remove_torrents(reaped_torrents);
+                stats.remove_peers(reaped_seeders, reaped_leechers);
+            }
+            thread::yield_now();
+        }
+        *self.leaderboards.write() = boards.build(time::get_time().sec);
+    }
+}
+
+#[test]
+fn reaping_in_batches() {
+    use tracker::announce::test_announce;
+    use time::Duration;
+
+    let config = TrackerConfig { reap_batch_size: 2, ..Default::default() };
+    let tracker = Tracker::new(config, Default::default());
+    for i in 0..10 {
+        tracker.handle_announce(test_announce(i, i)).unwrap();
+    }
+    {
+        let mut torrents = tracker.torrents.lock();
+        for i in 0..5 {
+            let t = torrents.get_mut(&InfoHash::from_bytes(&[i; 20]).unwrap()).unwrap();
+            t.last_action = SteadyTime::now() - Duration::seconds(3000);
         }
     }
+    tracker.reap();
+
+    assert!(tracker.torrents.lock().len() == 5);
+    assert!(tracker.reap_queue.lock().len() == 5);
+    assert!(tracker.stats.lock().torrents == 5);
+    assert!(tracker.stats.lock().leechers == 5);
+}
+
+#[test]
+fn stats_under_concurrency() {
+    use tracker::announce::test_announce;
+    use time::Duration;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Everything is stale right away, so the reaper keeps racing the announces.
+    let config = TrackerConfig {
+        reap_batch_size: 3,
+        min_torrent_update_interval: Duration::zero(),
+        min_peer_update_interval: Duration::zero(),
+        ..Default::default()
+    };
+    let tracker = Arc::new(Tracker::new(config, Default::default()));
+    let done = Arc::new(AtomicBool::new(false));
+    let reaper = {
+        let (tracker, done) = (tracker.clone(), done.clone());
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                tracker.reap();
+            }
+        })
+    };
+    let announcers: Vec<_> = (0..4u8)
+        .map(|n| {
+            let tracker = tracker.clone();
+            thread::spawn(move || {
+                for i in 0..2000usize {
+                    let peer = (i * 7 + n as usize) % 30;
+                    let action = match i % 5 {
+                        0 => Action::Completed,
+                        1 => Action::Stopped,
+                        2 => Action::Seeding,
+                        _ => Action::Leeching,
+                    };
+                    let announce = Announce {
+                        ul: i as u64,
+                        action: action,
+                        numwant: 5,
+                        ..test_announce((i % 8) as u8, peer as u8)
+                    };
+                    tracker.handle_announce(announce).unwrap();
+                    if i % 100 == 0 {
+                        tracker.get_stats().unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for announcer in announcers {
+        announcer.join().unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    reaper.join().unwrap();
+
+    let torrents = tracker.torrents.lock();
+    let stats = tracker.stats.lock();
+    assert!(stats.torrents == torrents.len() as u64);
+    assert!(stats.seeders == torrents.values().map(|t| t.get_stats().complete as u64).sum());
+    assert!(stats.leechers == torrents.values().map(|t| t.get_stats().incomplete as u64).sum());
+    assert!(stats.announces == 8000);
+    assert!(stats.completed == 1600);
+    assert!(stats.stopped == 1600);
 }
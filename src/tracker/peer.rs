@@ -1,172 +1,270 @@
-use tracker::announce::{Announce, AnnouncePeer};
+use tracker::announce::Announce;
+use tracker::peer_id::PeerId;
+
+use tracker::info_hash::InfoHash;
 
 use time::SteadyTime;
-use std::net::{SocketAddrV4, SocketAddrV6};
+use std::cmp;
+use std::net::{IpAddr, SocketAddrV4, SocketAddrV6};
 
 pub struct Peer {
-    pub id: String,
+    pub id: PeerId,
+    pub key: Option<String>,
+    /// The passkey the peer first announced with, in private mode.
+    pub passkey: Option<String>,
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
     pub last_action: SteadyTime,
     pub ipv4: Option<SocketAddrV4>,
     pub ipv6: Option<SocketAddrV6>,
+    /// Positions of the peer's compact entries in its `PeerList`.
+    pub slot4: Option<usize>,
+    pub slot6: Option<usize>,
 }
 
+#[derive(Clone, Debug)]
 pub struct Delta {
-    pub peer_id: String,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    /// Bytes transferred, as reported.
     pub upload: u64,
     pub download: u64,
+    /// Bytes counted towards the user's totals, after any modifiers.
+    pub credited_upload: u64,
+    pub credited_download: u64,
     pub left: u64,
+    /// Seconds since the peer's previous announce.
+    pub interval: u64,
+    /// Seconds between the peer's previous announce and this one if it seeded at both.
+    pub seed_time: u64,
+    /// Seeders in the swarm after this announce.
+    pub seeders: u64,
+    pub passkey: Option<String>,
+    /// Set if this announce was the peer's first completion of the torrent.
+    pub snatch: Option<Snatch>,
+    /// Reasons to doubt the reported amounts, if any.
+    pub suspicions: Vec<Suspicion>,
+}
+
+/// Signs of a client misreporting its transfer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Suspicion {
+    /// Bytes per second uploaded, above the allowed maximum.
+    UploadRate(u64),
+    UploadWithoutLeechers,
+    CountersBackwards,
+    LeftIncreased,
+}
+
+/// Percentages of the transferred bytes counted towards a user's totals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Credit {
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Who completed what, and when.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snatch {
     pub passkey: Option<String>,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: Option<IpAddr>,
+    /// Seconds since the Unix epoch.
+    pub time: i64,
 }
 
 impl Peer {
     pub fn new(a: &Announce) -> Peer {
         Peer {
-            id: a.peer_id.clone(),
+            id: a.peer_id,
+            key: a.key.clone(),
+            passkey: a.passkey.clone(),
             uploaded: a.ul,
             downloaded: a.dl,
             left: a.left,
             ipv4: a.ipv4,
             ipv6: a.ipv6,
+            slot4: None,
+            slot6: None,
             last_action: SteadyTime::now(),
         }
     }
 
     pub fn update(&mut self, a: &Announce) -> Delta {
+        let now = SteadyTime::now();
+        let upload = a.ul.saturating_sub(self.uploaded);
+        let download = a.dl.saturating_sub(self.downloaded);
+        let interval = cmp::max((now - self.last_action).num_seconds(), 0) as u64;
+        let mut suspicions = Vec::new();
+        if a.ul < self.uploaded || a.dl < self.downloaded {
+            suspicions.push(Suspicion::CountersBackwards);
+        }
+        if a.left > self.left {
+            suspicions.push(Suspicion::LeftIncreased);
+        }
         let d = Delta {
-            peer_id: self.id.clone(),
-            upload: if a.ul > self.uploaded {
-                a.ul - self.uploaded
-            } else {
-                0
-            },
-            download: if a.dl > self.downloaded {
-                a.dl - self.downloaded
-            } else {
-                0
-            },
-            left: if self.left > a.left {
-                self.left - a.left
-            } else {
-                0
-            },
+            info_hash: a.info_hash,
+            peer_id: self.id,
+            upload: upload,
+            download: download,
+            credited_upload: upload,
+            credited_download: download,
+            left: self.left.saturating_sub(a.left),
+            interval: interval,
+            seed_time: if self.left == 0 && a.left == 0 { interval } else { 0 },
+            seeders: 0,
             passkey: a.passkey.clone(),
+            snatch: None,
+            suspicions: suspicions,
         };
         self.uploaded = a.ul;
         self.downloaded = a.dl;
         self.left = a.left;
         self.ipv4 = a.ipv4;
         self.ipv6 = a.ipv6;
-        self.last_action = SteadyTime::now();
+        self.last_action = now;
         d
     }
 
-    pub fn get_announce_peer(&self) -> AnnouncePeer {
-        AnnouncePeer {
-            id: self.id.clone(),
-            ipv4: self.ipv4.clone(),
-            ipv6: self.ipv6.clone(),
+    /// Checks whether an announce may act on behalf of this peer. A peer which joined with a
+    /// key only accepts that key, peers without one can be updated by anyone. The key is never
+    /// added later, so nobody can claim a keyless peer.
+    pub fn is_owned_by(&self, a: &Announce) -> bool {
+        match (self.key.as_ref(), a.key.as_ref()) {
+            (Some(key), Some(akey)) => key == akey,
+            (Some(_), None) => false,
+            (None, _) => true,
         }
     }
 }
 
 impl Delta {
-    pub fn new(peer_id: String, passkey: Option<String>) -> Delta {
+    /// A delta of nothing, for announces of peers that weren't in the swarm yet.
+    pub fn new(a: &Announce) -> Delta {
         Delta {
-            peer_id: peer_id,
+            info_hash: a.info_hash,
+            peer_id: a.peer_id,
             upload: 0,
             download: 0,
+            credited_upload: 0,
+            credited_download: 0,
             left: 0,
-            passkey: passkey,
+            interval: 0,
+            seed_time: 0,
+            seeders: 0,
+            passkey: a.passkey.clone(),
+            snatch: None,
+            suspicions: Vec::new(),
+        }
+    }
+
+    pub fn is_suspicious(&self) -> bool {
+        !self.suspicions.is_empty()
+    }
+
+    /// Sets the credited amounts from the raw ones.
+    pub fn apply_credit(&mut self, credit: Credit) {
+        self.credited_upload = self.upload.saturating_mul(credit.upload) / 100;
+        self.credited_download = self.download.saturating_mul(credit.download) / 100;
+    }
+}
+
+impl Suspicion {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Suspicion::UploadRate(_) => "upload_rate",
+            Suspicion::UploadWithoutLeechers => "upload_without_leechers",
+            Suspicion::CountersBackwards => "counters_backwards",
+            Suspicion::LeftIncreased => "left_increased",
+        }
+    }
+}
+
+impl Credit {
+    /// Both modifiers applied one after the other.
+    pub fn combine(self, other: Credit) -> Credit {
+        Credit {
+            upload: self.upload * other.upload / 100,
+            download: self.download * other.download / 100,
+        }
+    }
+}
+
+impl Default for Credit {
+    fn default() -> Credit {
+        Credit {
+            upload: 100,
+            download: 100,
         }
     }
 }
 
 #[test]
 fn create_from_announce() {
-    use tracker::announce::Action;
-
-    let pid = String::from("pid");
-    let ipv4 = None;
-    let ipv6 = None;
-    let ul = 1;
-    let dl = 1;
-    let left = 1;
-    let announce =
-        Announce {
-            info_hash: String::from("hash"),
-            peer_id: pid.clone(),
-            passkey: None,
-            ipv4: ipv4.clone(),
-            ipv6: ipv6.clone(),
-            ul: ul,
-            dl: dl,
-            left: left,
-            action: Action::Seeding,
-            numwant: 1,
-            compact: true,
-        };
+    use tracker::announce::test_announce;
+
+    let announce = Announce { ul: 1, dl: 1, ipv4: None, ..test_announce(0, 1) };
     let peer = Peer::new(&announce);
-    assert!(peer.uploaded == ul);
-    assert!(peer.downloaded == dl);
-    assert!(peer.left == left);
-    assert!(peer.id == pid);
-    assert!(peer.ipv4 == ipv4);
-    assert!(peer.ipv6 == ipv6);
+    assert!(peer.uploaded == announce.ul);
+    assert!(peer.downloaded == announce.dl);
+    assert!(peer.left == announce.left);
+    assert!(peer.id == announce.peer_id);
+    assert!(peer.ipv4 == announce.ipv4);
+    assert!(peer.ipv6 == announce.ipv6);
 }
 
 #[test]
 fn peer_update() {
-    use tracker::announce::Action;
-
-    let pid = String::from("pid");
-    let ipv4 = None;
-    let ipv6 = None;
-    let ul = 1;
-    let dl = 1;
-    let left = 1;
-
-    let announce =
-        Announce {
-            info_hash: String::from("hash"),
-            peer_id: pid.clone(),
-            passkey: None,
-            ipv4: ipv4.clone(),
-            ipv6: ipv6.clone(),
-            ul: ul,
-            dl: dl,
-            left: left,
-            action: Action::Seeding,
-            numwant: 1,
-            compact: true,
-        };
+    use tracker::announce::{Action, test_announce};
 
-    let ipv4 = None;
-    let ipv6 = None;
-    let ul_2 = 2;
-    let dl_2 = 2;
-    let left_2 = 0;
-
-    let announce2 =
-        Announce {
-            info_hash: String::from("hash"),
-            peer_id: pid.clone(),
-            passkey: None,
-            ipv4: ipv4.clone(),
-            ipv6: ipv6.clone(),
-            ul: ul_2,
-            dl: dl_2,
-            left: left_2,
-            action: Action::Seeding,
-            numwant: 1,
-            compact: true,
-        };
+    let announce = Announce { ul: 1, dl: 1, action: Action::Seeding, ..test_announce(0, 1) };
+    let announce2 = Announce { ul: 2, dl: 2, left: 0, ..test_announce(0, 1) };
     let mut peer = Peer::new(&announce);
     let delta = peer.update(&announce2);
 
-    assert!(delta.upload == ul_2 - ul);
-    assert!(delta.download == dl_2 - dl);
-    assert!(delta.left == left - left_2);
+    assert!(delta.upload == announce2.ul - announce.ul);
+    assert!(delta.download == announce2.dl - announce.dl);
+    assert!(delta.left == announce.left - announce2.left);
+}
+
+#[test]
+fn suspicious_counters() {
+    use tracker::announce::test_announce;
+
+    let announce = Announce { ul: 1, dl: 1, ..test_announce(0, 1) };
+    let mut peer = Peer::new(&announce);
+    assert!(!peer.update(&Announce { ul: 2, dl: 2, left: 0, ..test_announce(0, 1) })
+        .is_suspicious());
+    let delta = peer.update(&announce);
+    assert!(delta.suspicions == vec![Suspicion::CountersBackwards, Suspicion::LeftIncreased]);
+}
+
+#[test]
+fn peer_key_ownership() {
+    use tracker::announce::{Action, test_announce};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let announce = Announce { key: Some(String::from("key")), ..test_announce(0, 1) };
+    let peer = Peer::new(&announce);
+
+    let moved = Announce {
+        key: Some(String::from("key")),
+        ipv4: Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881)),
+        action: Action::Stopped,
+        ..test_announce(0, 1)
+    };
+    assert!(peer.is_owned_by(&moved));
+
+    let spoofed = Announce {
+        key: Some(String::from("other")),
+        action: Action::Stopped,
+        ..test_announce(0, 1)
+    };
+    assert!(!peer.is_owned_by(&spoofed));
+
+    // Peers which never sent a key keep working when their address changes.
+    let keyless = Peer::new(&test_announce(0, 1));
+    assert!(keyless.is_owned_by(&Announce { key: None, ..moved }));
 }
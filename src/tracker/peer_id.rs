@@ -0,0 +1,55 @@
+use std::fmt;
+
+/// A peer's 20 byte id. Kept inline rather than as a `String` so that peers can be looked up
+/// and copied around without allocating.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    pub fn from_bytes(bytes: &[u8]) -> Option<PeerId> {
+        if bytes.len() != 20 {
+            return None;
+        }
+        let mut id = [0u8; 20];
+        id.copy_from_slice(bytes);
+        Some(PeerId(id))
+    }
+
+    pub fn from_hex(hex: &str) -> Option<PeerId> {
+        if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(hex.len() / 2);
+        for i in 0..hex.len() / 2 {
+            match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return None,
+            }
+        }
+        PeerId::from_bytes(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        let mut s = String::with_capacity(40);
+        for b in self.0.iter() {
+            s.push_str(&format!("{:02x}", b));
+        }
+        s
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
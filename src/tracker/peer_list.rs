@@ -0,0 +1,303 @@
+use tracker::announce::Announce;
+use tracker::peer::{Delta, Peer};
+use tracker::peer_id::PeerId;
+
+use std::cell::Cell;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::{RandomState, Values};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddrV4, SocketAddrV6};
+use time::{Duration, SteadyTime};
+
+const COMPACT_V4: usize = 6;
+const COMPACT_V6: usize = 18;
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(seed());
+}
+
+/// A set of peers along with their compact representations. The compact entries are kept up
+/// to date as peers come, go and move, so announces only need to copy a slice of them.
+pub struct PeerList {
+    peers: HashMap<PeerId, Peer>,
+    compact4: CompactList,
+    compact6: CompactList,
+}
+
+/// Fixed width compact peer entries, packed back to back. Removal moves the last entry into
+/// the freed slot, so `owners` is kept to find the peer whose slot changed.
+struct CompactList {
+    width: usize,
+    bytes: Vec<u8>,
+    owners: Vec<PeerId>,
+}
+
+impl PeerList {
+    pub fn new() -> PeerList {
+        PeerList {
+            peers: HashMap::new(),
+            compact4: CompactList::new(COMPACT_V4),
+            compact6: CompactList::new(COMPACT_V6),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<&Peer> {
+        self.peers.get(id)
+    }
+
+    pub fn values<'a>(&'a self) -> Values<'a, PeerId, Peer> {
+        self.peers.values()
+    }
+
+    /// Adds a peer, replacing any peer with the same id.
+    pub fn insert(&mut self, mut peer: Peer) {
+        self.remove(&peer.id);
+        if let Some(addr) = peer.ipv4 {
+            peer.slot4 = Some(self.compact4.push(peer.id, &compact_v4(&addr)));
+        }
+        if let Some(addr) = peer.ipv6 {
+            peer.slot6 = Some(self.compact6.push(peer.id, &compact_v6(&addr)));
+        }
+        self.peers.insert(peer.id, peer);
+    }
+
+    pub fn remove(&mut self, id: &PeerId) -> Option<Peer> {
+        let mut peer = match self.peers.remove(id) {
+            Some(peer) => peer,
+            None => return None,
+        };
+        if let Some(slot) = peer.slot4.take() {
+            if let Some(moved) = self.compact4.swap_remove(slot) {
+                self.peers.get_mut(&moved).unwrap().slot4 = Some(slot);
+            }
+        }
+        if let Some(slot) = peer.slot6.take() {
+            if let Some(moved) = self.compact6.swap_remove(slot) {
+                self.peers.get_mut(&moved).unwrap().slot6 = Some(slot);
+            }
+        }
+        Some(peer)
+    }
+
+    /// Updates an existing peer from an announce, returning None if there is no such peer.
+    pub fn update(&mut self, a: &Announce) -> Option<Delta> {
+        let (delta, moved) = match self.peers.get_mut(&a.peer_id) {
+            Some(peer) => {
+                let moved = peer.ipv4 != a.ipv4 || peer.ipv6 != a.ipv6;
+                (peer.update(a), moved)
+            }
+            None => return None,
+        };
+        // Address changes are rare, so re-adding the peer is good enough for those.
+        if moved {
+            let peer = self.remove(&a.peer_id).unwrap();
+            self.insert(peer);
+        }
+        Some(delta)
+    }
+
+    /// Removes the peers which haven't announced within `max_age`, appending them to `reaped`.
+    pub fn reap(&mut self, now: SteadyTime, max_age: &Duration, reaped: &mut Vec<Peer>) {
+        let expired: Vec<_> = self.peers
+            .values()
+            .filter(|peer| now - peer.last_action > *max_age)
+            .map(|peer| peer.id)
+            .collect();
+        for id in expired {
+            reaped.extend(self.remove(&id));
+        }
+    }
+
+    pub fn into_peers(self) -> Vec<Peer> {
+        self.peers.into_values().collect()
+    }
+
+    /// Appends up to `amount` compact IPv4 entries of peers other than `exclude`, starting
+    /// from a random peer so that every peer gets handed out. Ids of the chosen peers are
+    /// appended to `ids` if given.
+    pub fn sample4(&self,
+                   amount: usize,
+                   exclude: &PeerId,
+                   bytes: &mut Vec<u8>,
+                   ids: Option<&mut Vec<PeerId>>)
+                   -> usize {
+        let skip = self.peers.get(exclude).and_then(|peer| peer.slot4);
+        self.compact4.sample(amount, skip, bytes, ids)
+    }
+
+    /// Appends up to `amount` compact IPv6 entries, like `sample4`.
+    pub fn sample6(&self,
+                   amount: usize,
+                   exclude: &PeerId,
+                   bytes: &mut Vec<u8>,
+                   ids: Option<&mut Vec<PeerId>>)
+                   -> usize {
+        let skip = self.peers.get(exclude).and_then(|peer| peer.slot6);
+        self.compact6.sample(amount, skip, bytes, ids)
+    }
+}
+
+impl Default for PeerList {
+    fn default() -> PeerList {
+        PeerList::new()
+    }
+}
+
+impl CompactList {
+    fn new(width: usize) -> CompactList {
+        CompactList {
+            width: width,
+            bytes: Vec::new(),
+            owners: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    fn push(&mut self, id: PeerId, entry: &[u8]) -> usize {
+        self.bytes.extend_from_slice(entry);
+        self.owners.push(id);
+        self.owners.len() - 1
+    }
+
+    /// Removes the entry in `slot`, returning the owner of the entry moved into its place.
+    fn swap_remove(&mut self, slot: usize) -> Option<PeerId> {
+        let last = self.len() - 1;
+        self.owners.swap_remove(slot);
+        if slot != last {
+            let (head, tail) = self.bytes.split_at_mut(last * self.width);
+            head[slot * self.width..(slot + 1) * self.width].copy_from_slice(tail);
+        }
+        self.bytes.truncate(last * self.width);
+        self.owners.get(slot).cloned()
+    }
+
+    /// Copies out up to `amount` entries, leaving out the one in `skip`.
+    fn sample(&self,
+              amount: usize,
+              skip: Option<usize>,
+              bytes: &mut Vec<u8>,
+              mut ids: Option<&mut Vec<PeerId>>)
+              -> usize {
+        let len = self.len() - skip.map_or(0, |_| 1);
+        let amount = cmp::min(amount, len);
+        if amount == 0 {
+            return 0;
+        }
+        // The chosen entries wrap around the end of the list, so at most two copies are needed,
+        // each split in two around the skipped entry.
+        let start = random() as usize % len;
+        let first = cmp::min(len - start, amount);
+        let rest = amount - first;
+        for &(from, to) in &[(start, start + first), (0, rest)] {
+            // Entries from the skipped one on are one further along.
+            let ranges = match skip {
+                Some(slot) if slot < from => [(from + 1, to + 1), (0, 0)],
+                Some(slot) if slot < to => [(from, slot), (slot + 1, to + 1)],
+                _ => [(from, to), (0, 0)],
+            };
+            for &(from, to) in &ranges {
+                bytes.extend_from_slice(&self.bytes[from * self.width..to * self.width]);
+                if let Some(ref mut ids) = ids {
+                    ids.extend_from_slice(&self.owners[from..to]);
+                }
+            }
+        }
+        amount
+    }
+}
+
+fn compact_v4(addr: &SocketAddrV4) -> [u8; COMPACT_V4] {
+    let ip = addr.ip().octets();
+    let port = addr.port();
+    [ip[0], ip[1], ip[2], ip[3], (port >> 8) as u8, port as u8]
+}
+
+fn compact_v6(addr: &SocketAddrV6) -> [u8; COMPACT_V6] {
+    let mut entry = [0u8; COMPACT_V6];
+    entry[..16].copy_from_slice(&addr.ip().octets());
+    entry[16] = (addr.port() >> 8) as u8;
+    entry[17] = addr.port() as u8;
+    entry
+}
+
+/// Xorshift, which is plenty for spreading peer selection around.
+fn random() -> u64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+}
+
+fn seed() -> u64 {
+    // Xorshift gets stuck on zero.
+    RandomState::new().build_hasher().finish() | 1
+}
+
+#[test]
+fn compact_entries_follow_peers() {
+    use tracker::announce::test_announce;
+    use std::net::Ipv4Addr;
+
+    let announce = |i: u8, ip: u8| {
+        Announce {
+            ipv4: Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, ip), 6881)),
+            ..test_announce(0, i)
+        }
+    };
+    let mut list = PeerList::new();
+    for i in 0..3 {
+        list.insert(Peer::new(&announce(i, i)));
+    }
+    let removed = PeerId::from_bytes(&[0; 20]).unwrap();
+    list.remove(&removed);
+    list.update(&announce(1, 9));
+
+    let mut bytes = Vec::new();
+    let mut ids = Vec::new();
+    assert!(list.sample4(25, &removed, &mut bytes, Some(&mut ids)) == 2);
+    for (entry, id) in bytes.chunks(COMPACT_V4).zip(ids.iter()) {
+        let peer = list.get(id).unwrap();
+        assert!(entry == &compact_v4(&peer.ipv4.unwrap())[..]);
+    }
+    let moved = list.get(&PeerId::from_bytes(&[1; 20]).unwrap()).unwrap();
+    assert!(moved.ipv4 == Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 9), 6881)));
+}
+
+#[test]
+fn sampling_leaves_out_the_announcing_peer() {
+    use tracker::announce::test_announce;
+
+    let mut list = PeerList::new();
+    for i in 0..5 {
+        list.insert(Peer::new(&test_announce(0, i)));
+    }
+    for i in 0..5 {
+        let exclude = PeerId::from_bytes(&[i; 20]).unwrap();
+        for amount in 1..6 {
+            let mut bytes = Vec::new();
+            let mut ids = Vec::new();
+            let sampled = list.sample4(amount, &exclude, &mut bytes, Some(&mut ids));
+            assert!(sampled == cmp::min(amount, 4) && ids.len() == sampled);
+            assert!(!ids.contains(&exclude));
+            for (entry, id) in bytes.chunks(COMPACT_V4).zip(ids.iter()) {
+                assert!(entry == &compact_v4(&list.get(id).unwrap().ipv4.unwrap())[..]);
+            }
+        }
+    }
+}
@@ -1,18 +1,19 @@
 use tracker::torrent::Stats;
+use tracker::info_hash::InfoHash;
 use std::collections::HashMap;
 
 pub struct Scrape {
-    pub torrents: Vec<String>
+    pub torrents: Vec<InfoHash>
 }
 
 #[derive(Debug)]
 pub struct ScrapeResponse {
-    pub torrents: HashMap<String, Stats>,
+    pub torrents: HashMap<InfoHash, Stats>,
 }
 
 
 impl Scrape {
-    pub fn new(torrents: Vec<String>) -> Scrape {
+    pub fn new(torrents: Vec<InfoHash>) -> Scrape {
         Scrape { torrents: torrents }
     }
 }
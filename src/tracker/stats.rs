@@ -1,53 +1,221 @@
 use time::SteadyTime;
 
+/// Seconds of history kept for rates.
+const WINDOW: usize = 300;
+
+/// Tracker wide statistics. Everything is kept up to date as announces and reaps happen, so
+/// the counts are exact as long as they're only changed while holding the tracker's locks.
 #[derive(Debug)]
 pub struct Stats {
+    pub torrents: u64,
+    pub seeders: u64,
+    pub leechers: u64,
     pub announces: u64,
+    /// Announces of peers joining a swarm, whether or not they sent `started`.
+    pub started: u64,
+    pub completed: u64,
+    pub stopped: u64,
     pub scrapes: u64,
-    pub torrents: u64,
-    pub peers: u64,
+    /// Bytes peers reported transferring since their previous announce.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    announce_window: Window,
+    scrape_window: Window,
     start_time: SteadyTime,
-    clear_time: SteadyTime,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+    Update,
+}
+
+/// Counts of the last `WINDOW` seconds, one bucket per second.
+#[derive(Debug)]
+struct Window {
+    buckets: [u64; WINDOW],
+    /// The second the newest bucket belongs to.
+    last: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
 }
 
 impl Stats {
     pub fn new() -> Stats {
         Stats {
+            torrents: 0,
+            seeders: 0,
+            leechers: 0,
             announces: 0,
+            started: 0,
+            completed: 0,
+            stopped: 0,
             scrapes: 0,
-            torrents: 0,
-            peers: 0,
+            uploaded: 0,
+            downloaded: 0,
+            announce_window: Window::new(),
+            scrape_window: Window::new(),
             start_time: SteadyTime::now(),
-            clear_time: SteadyTime::now(),
         }
     }
 
-    pub fn update(&mut self) {
-        self.clear_time = SteadyTime::now();
-        self.announces = 0;
-        self.scrapes = 0;
+    pub fn record_announce(&mut self, event: Event, uploaded: u64, downloaded: u64) {
+        let now = self.uptime();
+        self.announces += 1;
+        match event {
+            Event::Started => self.started += 1,
+            Event::Completed => self.completed += 1,
+            Event::Stopped => self.stopped += 1,
+            Event::Update => {}
+        }
+        self.uploaded = self.uploaded.saturating_add(uploaded);
+        self.downloaded = self.downloaded.saturating_add(downloaded);
+        self.announce_window.record(now, 1);
+    }
+
+    pub fn record_scrape(&mut self) {
+        let now = self.uptime();
+        self.scrapes += 1;
+        self.scrape_window.record(now, 1);
+    }
+
+    pub fn add_peers(&mut self, seeders: u64, leechers: u64) {
+        self.seeders += seeders;
+        self.leechers += leechers;
+    }
+
+    /// Peers are only removed after having been added, so these should never saturate.
+    pub fn remove_peers(&mut self, seeders: u64, leechers: u64) {
+        self.seeders = self.seeders.saturating_sub(seeders);
+        self.leechers = self.leechers.saturating_sub(leechers);
+    }
+
+    pub fn remove_torrents(&mut self, torrents: u64) {
+        self.torrents = self.torrents.saturating_sub(torrents);
+    }
+
+    fn uptime(&self) -> u64 {
+        (SteadyTime::now() - self.start_time).num_seconds() as u64
+    }
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            buckets: [0; WINDOW],
+            last: 0,
+        }
+    }
+
+    fn record(&mut self, now: u64, count: u64) {
+        if now > self.last {
+            // Clear the buckets of the seconds nothing happened in.
+            let stale = if now - self.last < WINDOW as u64 {
+                now - self.last
+            } else {
+                WINDOW as u64
+            };
+            for second in now - stale + 1..now + 1 {
+                self.buckets[(second % WINDOW as u64) as usize] = 0;
+            }
+            self.last = now;
+        }
+        self.buckets[(self.last % WINDOW as u64) as usize] += count;
+    }
+
+    /// Average per second over the `secs` full seconds before `now`, or fewer if `now` is
+    /// earlier than that.
+    fn rate(&self, now: u64, secs: u64) -> f64 {
+        let secs = if secs < now { secs } else { now };
+        if secs == 0 {
+            return 0.0;
+        }
+        let mut total = 0;
+        for second in now - secs..now {
+            // Buckets for seconds after the newest one or before the window are stale.
+            if second <= self.last && self.last - second < WINDOW as u64 {
+                total += self.buckets[(second % WINDOW as u64) as usize];
+            }
+        }
+        total as f64 / secs as f64
     }
 }
 
 #[derive(Debug)]
 pub struct StatsResponse {
-    pub announce_rate: u64,
-    pub scrape_rate: u64,
+    pub announce_rate: f64,
+    pub announce_rate_5m: f64,
+    pub scrape_rate: f64,
+    pub scrape_rate_5m: f64,
     pub torrents: u64,
+    pub seeders: u64,
+    pub leechers: u64,
     pub peers: u64,
+    pub announces: u64,
+    pub started: u64,
+    pub completed: u64,
+    pub stopped: u64,
+    pub scrapes: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
     pub uptime: u64,
+    /// Private tracker deltas and rows waiting to be written to the backend.
+    pub queued_deltas: u64,
+    pub queued_rows: u64,
+    pub journaled_rows: u64,
 }
 
 impl StatsResponse {
+    /// Rates are averaged over the last minute, and over the last five minutes for the `_5m`
+    /// variants.
     pub fn new(stats: &Stats) -> StatsResponse {
-        let secs = (SteadyTime::now() - stats.clear_time).num_seconds();
-        let uptime = (SteadyTime::now() - stats.start_time).num_seconds();
+        let uptime = stats.uptime();
         StatsResponse {
-            announce_rate: stats.announces/secs as u64,
-            scrape_rate: stats.scrapes/secs as u64,
+            announce_rate: stats.announce_window.rate(uptime, 60),
+            announce_rate_5m: stats.announce_window.rate(uptime, 300),
+            scrape_rate: stats.scrape_window.rate(uptime, 60),
+            scrape_rate_5m: stats.scrape_window.rate(uptime, 300),
             torrents: stats.torrents,
-            peers: stats.peers,
-            uptime: uptime as u64,
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            peers: stats.seeders + stats.leechers,
+            announces: stats.announces,
+            started: stats.started,
+            completed: stats.completed,
+            stopped: stats.stopped,
+            scrapes: stats.scrapes,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            uptime: uptime,
+            queued_deltas: 0,
+            queued_rows: 0,
+            journaled_rows: 0,
         }
     }
 }
+
+#[test]
+fn sliding_window_rates() {
+    let mut window = Window::new();
+    assert!(window.rate(0, 60) == 0.0);
+    for second in 0..10 {
+        window.record(second, 6);
+    }
+    // The current second isn't over yet, so it doesn't count.
+    assert!(window.rate(9, 60) == 6.0);
+    assert!(window.rate(10, 60) == 6.0);
+    assert!(window.rate(20, 10) == 0.0);
+    assert!(window.rate(20, 20) == 3.0);
+
+    // Seconds without anything recorded are cleared out once the window wraps around.
+    window.record(WINDOW as u64 + 5, 1);
+    assert!(window.rate(WINDOW as u64 + 6, 300) == (1.0 + 4.0 * 6.0) / 300.0);
+    window.record(10 * WINDOW as u64, 3);
+    assert!(window.rate(10 * WINDOW as u64 + 1, 60) == 3.0 / 60.0);
+}
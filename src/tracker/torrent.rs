@@ -1,88 +1,176 @@
-use std::collections::HashMap;
-use time::SteadyTime;
+use time::{self, SteadyTime};
 use time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 
-use tracker::announce::{Action, Announce, AnnouncePeer};
-use tracker::peer::{Peer, Delta};
+use tracker::announce::{Action, Announce};
+use tracker::peer::{Credit, Peer, Delta, Snatch, Suspicion};
+use tracker::peer_list::PeerList;
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use response::error::ErrorResponse;
 
 pub struct Torrent {
-    hash: String,
+    hash: InfoHash,
     snatches: u64,
-    seeders: HashMap<String, Peer>,
-    leechers: HashMap<String, Peer>,
+    seeders: PeerList,
+    leechers: PeerList,
+    history: VecDeque<Sample>,
+    recent_snatches: HourlyCounts,
+    /// Everyone who completed the torrent over the last day, with the time they did, so that
+    /// repeated completes aren't counted as snatches again.
+    completed: HashMap<Completer, i64>,
+    /// When a leecher was last seen in the swarm, in seconds since the Unix epoch.
+    last_leecher: Option<i64>,
     pub last_action: SteadyTime,
 }
 
-#[derive(Debug)]
+/// Who completed a torrent, by passkey where there is one.
+#[derive(PartialEq, Eq, Hash)]
+enum Completer {
+    Passkey(String),
+    Peer(PeerId),
+}
+
+/// Counts over the last day, one bucket per hour.
+struct HourlyCounts {
+    counts: [u32; 24],
+    /// Hours since the Unix epoch of the newest bucket.
+    hour: i64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Stats {
     pub complete: i64,
     pub incomplete: i64,
     pub downloaded: i64,
 }
 
+/// A torrent's stats as they were at some point.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    pub stats: Stats,
+}
+
+/// A torrent's current stats along with their history, oldest sample first.
+#[derive(Debug)]
+pub struct TorrentResponse {
+    pub info_hash: InfoHash,
+    pub stats: Stats,
+    pub history: Vec<Sample>,
+}
+
+/// Peers handed out in an announce response, as compact entries.
+#[derive(Default)]
 pub struct Peers {
-    pub peers4: Vec<AnnouncePeer>,
-    pub peers6: Vec<AnnouncePeer>,
+    pub peers4: Vec<u8>,
+    pub peers6: Vec<u8>,
+    /// Ids of the peers in the same order, only filled in for non-compact responses.
+    pub ids4: Vec<PeerId>,
+    pub ids6: Vec<PeerId>,
 }
 
 impl Torrent {
-    pub fn new(hash: String) -> Torrent {
+    pub fn new(hash: InfoHash) -> Torrent {
         Torrent {
             hash: hash,
             snatches: 0,
-            seeders: HashMap::new(),
-            leechers: HashMap::new(),
+            seeders: PeerList::new(),
+            leechers: PeerList::new(),
+            history: VecDeque::new(),
+            recent_snatches: HourlyCounts::new(),
+            completed: HashMap::new(),
+            last_leecher: None,
             last_action: SteadyTime::now(),
         }
     }
 
-    pub fn update(&mut self, announce: &Announce) -> Delta {
+    /// Updates the announcing peer, crediting its transfer with `credit`.
+    pub fn update(&mut self, a: &Announce, credit: Credit) -> Result<Delta, ErrorResponse> {
+        if !self.is_authorized(a) {
+            return Err(ErrorResponse::BadKey);
+        }
         self.last_action = SteadyTime::now();
-        let ref a = *announce;
-        match a.action {
-            Action::Seeding => {
-                if self.seeders.contains_key(&a.peer_id) {
-                    match self.seeders.get_mut(&a.peer_id) {
-                        Some(peer) => peer.update(&a),
-                        None => Delta::new(a.peer_id.clone(), announce.passkey.clone()),
+        let now = time::get_time().sec;
+        // Leechers are counted before the announce changes the swarm, the peer itself aside.
+        if self.leechers.len() > self.leechers.get(&a.peer_id).map_or(0, |_| 1) {
+            self.last_leecher = Some(now);
+        }
+        let mut delta = match a.action {
+            Action::Seeding => update_peer(&mut self.seeders, &mut self.leechers, a),
+            Action::Leeching => update_peer(&mut self.leechers, &mut self.seeders, a),
+            Action::Completed => {
+                // Peers already seeding or which completed before, even if they stopped in
+                // between, have been counted, a repeated completed event is just an update.
+                let (mut peer, leeching) = match self.leechers.remove(&a.peer_id) {
+                    Some(p) => (p, true),
+                    None => {
+                        match self.seeders.remove(&a.peer_id) {
+                            Some(p) => (p, false),
+                            None => (Peer::new(a), true),
+                        }
                     }
-                } else {
-                    self.seeders.insert(a.peer_id.clone(), Peer::new(&a));
-                    Delta::new(a.peer_id.clone(), announce.passkey.clone())
+                };
+                let completer = match a.passkey {
+                    Some(ref passkey) => Completer::Passkey(passkey.clone()),
+                    None => Completer::Peer(a.peer_id),
+                };
+                let snatched = leeching && !self.completed.contains_key(&completer);
+                if snatched {
+                    self.completed.insert(completer, now);
                 }
-            }
-            Action::Leeching => {
-                if self.leechers.contains_key(&a.peer_id) {
-                    match self.leechers.get_mut(&a.peer_id) {
-                        Some(peer) => peer.update(&a),
-                        None => Delta::new(a.peer_id.clone(), announce.passkey.clone()),
-                    }
-                } else {
-                    self.leechers.insert(a.peer_id.clone(), Peer::new(&a));
-                    Delta::new(a.peer_id.clone(), announce.passkey.clone())
+                let mut d = peer.update(a);
+                self.seeders.insert(peer);
+                if snatched {
+                    self.snatches += 1;
+                    self.recent_snatches.add(now / 3600);
+                    d.snatch = Some(Snatch {
+                        passkey: a.passkey.clone(),
+                        info_hash: self.hash,
+                        peer_id: a.peer_id,
+                        ip: a.ipv4
+                            .map(|addr| IpAddr::V4(*addr.ip()))
+                            .or_else(|| a.ipv6.map(|addr| IpAddr::V6(*addr.ip()))),
+                        time: now,
+                    });
                 }
-            }
-            Action::Completed => {
-                let mut peer = match self.leechers.remove(&a.peer_id) {
-                    Some(p) => p,
-                    None => Peer::new(&a),
-                };
-                let d = peer.update(&a);
-                self.seeders.insert(a.peer_id.clone(), peer);
-                self.snatches += 1;
                 d
             }
             Action::Stopped => {
                 match (self.leechers.remove(&a.peer_id),
                        self.seeders.remove(&a.peer_id)) {
-                    (Some(ref mut peer), _) => peer.update(&a),
-                    (_, Some(ref mut peer)) => peer.update(&a),
-                    (None, None) => Delta::new(a.peer_id.clone(), announce.passkey.clone()),
+                    (Some(ref mut peer), _) => peer.update(a),
+                    (_, Some(ref mut peer)) => peer.update(a),
+                    (None, None) => Delta::new(a),
                 }
             }
+        };
+        // Uploads need someone to download them at some point since the previous announce.
+        if delta.upload > 0 &&
+           self.last_leecher.is_none_or(|seen| now - seen > delta.interval as i64) {
+            delta.suspicions.push(Suspicion::UploadWithoutLeechers);
+        }
+        if !self.leechers.is_empty() {
+            self.last_leecher = Some(now);
+        }
+        delta.seeders = self.seeders.len() as u64;
+        delta.apply_credit(credit);
+        Ok(delta)
+    }
+
+    fn is_authorized(&self, a: &Announce) -> bool {
+        match self.seeders.get(&a.peer_id).or_else(|| self.leechers.get(&a.peer_id)) {
+            Some(peer) => peer.is_owned_by(a),
+            None => true,
         }
     }
 
+    pub fn hash(&self) -> &InfoHash {
+        &self.hash
+    }
+
     pub fn get_stats(&self) -> Stats {
         Stats {
             complete: self.seeders.len() as i64,
@@ -91,72 +179,74 @@ impl Torrent {
         }
     }
 
-    pub fn get_peers(&self, amount: u8, action: Action) -> Peers {
-        let mut peers = Vec::with_capacity(amount as usize);
-        let mut peers6 = Vec::with_capacity(amount as usize);
-        match action {
-            Action::Leeching => {
-                let count = get_peers(&mut peers, &mut peers6, &self.seeders, amount);
-                if count == amount {
-                    Peers {
-                        peers4: peers,
-                        peers6: peers6,
-                    }
-                } else {
-                    get_peers(&mut peers, &mut peers6, &self.leechers, amount - count);
-                    Peers {
-                        peers4: peers,
-                        peers6: peers6,
-                    }
-                }
-            }
-            Action::Stopped => {
-                Peers {
-                    peers4: peers,
-                    peers6: peers6,
-                }
-            }
-            _ => {
-                let _count = get_peers(&mut peers, &mut peers6, &self.leechers, amount);
-                Peers {
-                    peers4: peers,
-                    peers6: peers6,
-                }
-            }
+    /// Fills `peers` with up to `amount` peers of each address family for the announcing peer
+    /// `id` to connect to, itself left out. Leechers are handed seeders first.
+    pub fn get_peers(&self,
+                     id: &PeerId,
+                     amount: u8,
+                     action: Action,
+                     with_ids: bool,
+                     peers: &mut Peers) {
+        let sources = match action {
+            Action::Leeching => [Some(&self.seeders), Some(&self.leechers)],
+            Action::Stopped => [None, None],
+            _ => [Some(&self.leechers), None],
+        };
+        let (mut wanted4, mut wanted6) = (amount as usize, amount as usize);
+        for list in sources.iter().filter_map(|l| *l) {
+            let Peers { ref mut peers4, ref mut peers6, ref mut ids4, ref mut ids6 } = *peers;
+            wanted4 -= list.sample4(wanted4, id, peers4, if with_ids { Some(ids4) } else { None });
+            wanted6 -= list.sample6(wanted6, id, peers6, if with_ids { Some(ids6) } else { None });
         }
     }
 
-    pub fn reap(&mut self, min_update_int: &Duration) {
-        // TODO use a config value for the max time
-        let to_del: Vec<_> = self.leechers
-                                 .iter()
-                                 .filter_map(|(k, peer)| {
-                                     if SteadyTime::now() - peer.last_action >
-                                        *min_update_int {
-                                         Some(k.clone())
-                                     } else {
-                                         None
-                                     }
-                                 })
-                                 .collect();
-        for item in to_del {
-            self.leechers.remove(&item);
-        }
+    pub fn get_webrtc_peers(&self, amount: usize, exclude: &PeerId) -> Vec<PeerId> {
+        self.seeders
+            .values()
+            .chain(self.leechers.values())
+            .filter(|peer| peer.ipv4.is_none() && peer.ipv6.is_none() && peer.id != *exclude)
+            .take(amount)
+            .map(|peer| peer.id)
+            .collect()
+    }
 
-        let to_del: Vec<_> = self.seeders
-                                 .iter()
-                                 .filter_map(|(k, peer)| {
-                                     if SteadyTime::now() - peer.last_action >
-                                        *min_update_int {
-                                         Some(k.clone())
-                                     } else {
-                                         None
-                                     }
-                                 })
-                                 .collect();
-        for item in to_del {
-            self.seeders.remove(&item);
+    /// Removes the peers which haven't announced within `min_update_int`, returning them.
+    pub fn reap(&mut self, now: SteadyTime, min_update_int: &Duration) -> Vec<Peer> {
+        let mut reaped = Vec::new();
+        self.leechers.reap(now, min_update_int, &mut reaped);
+        self.seeders.reap(now, min_update_int, &mut reaped);
+        reaped
+    }
+
+    pub fn into_peers(self) -> Vec<Peer> {
+        let mut peers = self.leechers.into_peers();
+        peers.extend(self.seeders.into_peers());
+        peers
+    }
+
+    /// Takes a sample of the current stats, dropping the oldest ones past `max` samples.
+    pub fn record_history(&mut self, time: i64, max: usize) {
+        while !self.history.is_empty() && self.history.len() >= max {
+            self.history.pop_front();
         }
+        if max > 0 {
+            let stats = self.get_stats();
+            self.history.push_back(Sample { time: time, stats: stats });
+        }
+    }
+
+    pub fn history(&self) -> &VecDeque<Sample> {
+        &self.history
+    }
+
+    /// Forgets who completed the torrent more than a day before `time`.
+    pub fn forget_completions(&mut self, time: i64) {
+        self.completed.retain(|_, completed| time - *completed < 24 * 3600);
+    }
+
+    /// Completions over the 24 hours up to `time`.
+    pub fn snatches_24h(&self, time: i64) -> u64 {
+        self.recent_snatches.total(time / 3600)
     }
 
     pub fn get_peer_count(&self) -> u64 {
@@ -164,32 +254,143 @@ impl Torrent {
     }
 }
 
-fn get_peers(peers: &mut Vec<AnnouncePeer>,
-             peers6: &mut Vec<AnnouncePeer>,
-             peer_dict: &HashMap<String, Peer>,
-             wanted: u8)
-             -> u8 {
-    let mut count = 0;
-    for peer in peer_dict.values() {
-        if count == wanted {
-            break;
+impl HourlyCounts {
+    fn new() -> HourlyCounts {
+        HourlyCounts {
+            counts: [0; 24],
+            hour: 0,
         }
-        match (peer.ipv4, peer.ipv6) {
-            (Some(_), Some(_)) => {
-                peers.push(peer.get_announce_peer());
-                peers6.push(peer.get_announce_peer());
-                count += 1;
-            }
-            (Some(_), None) => {
-                peers.push(peer.get_announce_peer());
-                count += 1;
-            }
-            (None, Some(_)) => {
-                peers6.push(peer.get_announce_peer());
-                count += 1;
+    }
+
+    fn add(&mut self, hour: i64) {
+        if hour > self.hour {
+            let stale = if hour - self.hour < 24 { hour - self.hour } else { 24 };
+            for h in hour - stale + 1..hour + 1 {
+                self.counts[(h % 24) as usize] = 0;
             }
-            (None, None) => {}
+            self.hour = hour;
         }
+        self.counts[(self.hour % 24) as usize] += 1;
+    }
+
+    fn total(&self, hour: i64) -> u64 {
+        (hour - 23..hour + 1)
+            .filter(|&h| h <= self.hour && self.hour - h < 24)
+            .map(|h| self.counts[(h % 24) as usize] as u64)
+            .sum()
+    }
+}
+
+/// Updates the peer in `list`, moving it over from `other` if it switched between seeding and
+/// leeching without a `completed` event, so that it's never counted twice.
+fn update_peer(list: &mut PeerList, other: &mut PeerList, a: &Announce) -> Delta {
+    if let Some(delta) = list.update(a) {
+        return delta;
     }
-    count
+    match other.remove(&a.peer_id) {
+        Some(mut peer) => {
+            let delta = peer.update(a);
+            list.insert(peer);
+            delta
+        }
+        None => {
+            list.insert(Peer::new(a));
+            Delta::new(a)
+        }
+    }
+}
+
+#[test]
+fn history_is_bounded() {
+    let mut torrent = Torrent::new(InfoHash::from_bytes(&[0; 20]).unwrap());
+    for time in 0..10 {
+        torrent.record_history(time, 3);
+    }
+    let times: Vec<_> = torrent.history().iter().map(|s| s.time).collect();
+    assert!(times == vec![7, 8, 9]);
+    torrent.record_history(10, 0);
+    assert!(torrent.history().is_empty());
+}
+
+#[test]
+fn snatches_over_the_last_day() {
+    let mut counts = HourlyCounts::new();
+    counts.add(100);
+    counts.add(100);
+    counts.add(110);
+    assert!(counts.total(110) == 3);
+    assert!(counts.total(123) == 3);
+    assert!(counts.total(124) == 1);
+    counts.add(140);
+    assert!(counts.total(140) == 1);
+}
+
+#[test]
+fn repeated_completes_snatch_once() {
+    use tracker::announce::test_announce;
+    use std::net::Ipv4Addr;
+
+    let announce = Announce {
+        passkey: Some(String::from("key")),
+        left: 0,
+        action: Action::Completed,
+        ..test_announce(0, 1)
+    };
+    let mut torrent = Torrent::new(announce.info_hash);
+    let snatch = torrent.update(&announce, Credit::default()).unwrap().snatch.unwrap();
+    assert!(snatch.passkey == announce.passkey && snatch.peer_id == announce.peer_id);
+    assert!(snatch.ip == Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+    assert!(torrent.update(&announce, Credit::default()).unwrap().snatch.is_none());
+    assert!(torrent.get_stats().downloaded == 1 && torrent.get_stats().complete == 1);
+    // Nor after stopping in between.
+    let stopped = Announce {
+        passkey: Some(String::from("key")),
+        left: 0,
+        action: Action::Stopped,
+        ..test_announce(0, 1)
+    };
+    torrent.update(&stopped, Credit::default()).unwrap();
+    assert!(torrent.get_stats().complete == 0);
+    assert!(torrent.update(&announce, Credit::default()).unwrap().snatch.is_none());
+    assert!(torrent.get_stats().downloaded == 1 && torrent.get_stats().complete == 1);
+    // Completions are only remembered for a day.
+    let time = torrent.completed[&Completer::Passkey(String::from("key"))];
+    torrent.forget_completions(time + 3600);
+    assert!(torrent.completed.len() == 1);
+    torrent.forget_completions(time + 24 * 3600);
+    assert!(torrent.completed.is_empty());
+}
+
+#[test]
+fn uploads_need_leechers() {
+    use tracker::announce::test_announce;
+
+    let leecher = test_announce(0, 1);
+    let seeder = Announce { left: 0, action: Action::Seeding, ..test_announce(0, 2) };
+    let mut torrent = Torrent::new(leecher.info_hash);
+    torrent.update(&leecher, Credit::default()).unwrap();
+    torrent.update(&seeder, Credit::default()).unwrap();
+    // The only leecher finishing doesn't make the upload it got suspicious.
+    let completed = Announce { left: 0, action: Action::Completed, ..test_announce(0, 1) };
+    torrent.update(&completed, Credit::default()).unwrap();
+    let delta = torrent.update(&Announce { ul: 100, ..seeder }, Credit::default()).unwrap();
+    assert!(!delta.is_suspicious());
+
+    let mut torrent = Torrent::new(leecher.info_hash);
+    let seeder = Announce { left: 0, action: Action::Seeding, ..test_announce(0, 2) };
+    torrent.update(&seeder, Credit::default()).unwrap();
+    let delta = torrent.update(&Announce { ul: 100, ..seeder }, Credit::default()).unwrap();
+    assert!(delta.suspicions == vec![Suspicion::UploadWithoutLeechers]);
+}
+
+#[test]
+fn keyless_peers_cant_be_claimed() {
+    use tracker::announce::test_announce;
+
+    let announce = test_announce(0, 1);
+    let mut torrent = Torrent::new(announce.info_hash);
+    torrent.update(&announce, Credit::default()).unwrap();
+    let claim = Announce { key: Some(String::from("other")), ..test_announce(0, 1) };
+    torrent.update(&claim, Credit::default()).unwrap();
+    assert!(torrent.update(&test_announce(0, 1), Credit::default()).is_ok());
 }
@@ -0,0 +1,284 @@
+use response::error::ErrorResponse;
+use response::success::SuccessResponse;
+use tracker::Tracker;
+use tracker::announce::{Action, Announce};
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use tracker::scrape::Scrape;
+use config::ListenerConfig;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp;
+use std::io;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Connection ids are derived from the client address and the current time window, and
+/// remain valid for the current and the previous window.
+const CONNECTION_WINDOW: u64 = 60;
+
+/// UDP tracker protocol as described in BEP 15.
+pub struct UdpHandler {
+    tracker: Arc<Tracker>,
+    socket: UdpSocket,
+    secret: RandomState,
+}
+
+impl UdpHandler {
+    pub fn start(tracker: Arc<Tracker>, config: ListenerConfig) -> io::Result<()> {
+        if cfg!(feature = "private") && config.private {
+            error!("UDP listener {} can't authenticate passkeys, not starting it!",
+                   config.address);
+            return Ok(());
+        }
+        let socket = try!(UdpSocket::bind(config.address.as_str()));
+        let handler = UdpHandler::new(tracker, socket);
+        info!("UDP interface listening on {}!", config.address);
+        handler.serve();
+        Ok(())
+    }
+
+    fn new(tracker: Arc<Tracker>, socket: UdpSocket) -> UdpHandler {
+        UdpHandler {
+            tracker: tracker,
+            socket: socket,
+            secret: RandomState::new(),
+        }
+    }
+
+    fn serve(&self) {
+        let mut buf = [0u8; 2048];
+        let mut resp = Vec::with_capacity(2048);
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Failed to receive UDP packet: {}", e);
+                    continue;
+                }
+            };
+            resp.clear();
+            self.handle_packet(&buf[..len], addr, &mut resp);
+            if !resp.is_empty() {
+                if let Err(e) = self.socket.send_to(&resp, addr) {
+                    debug!("Failed to send UDP response to {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&self, packet: &[u8], addr: SocketAddr, resp: &mut Vec<u8>) {
+        if packet.len() < 16 {
+            return;
+        }
+        let connection_id = read_u64(&packet[0..8]);
+        let action = read_u32(&packet[8..12]);
+        let transaction_id = read_u32(&packet[12..16]);
+        let body = &packet[16..];
+
+        let result = match action {
+            ACTION_CONNECT => {
+                if connection_id != PROTOCOL_ID {
+                    return;
+                }
+                write_u32(resp, ACTION_CONNECT);
+                write_u32(resp, transaction_id);
+                write_u64(resp, self.connection_id(&addr, 0));
+                Ok(())
+            }
+            _ if !self.is_connected(connection_id, &addr) => Err(ErrorResponse::BadAuth),
+            ACTION_ANNOUNCE => self.handle_announce(body, addr, transaction_id, resp),
+            ACTION_SCRAPE => self.handle_scrape(body, transaction_id, resp),
+            _ => Err(ErrorResponse::BadAction),
+        };
+
+        if let Err(e) = result {
+            resp.clear();
+            write_u32(resp, ACTION_ERROR);
+            write_u32(resp, transaction_id);
+            resp.extend_from_slice(e.reason().as_bytes());
+        }
+    }
+
+    fn handle_announce(&self,
+                       body: &[u8],
+                       addr: SocketAddr,
+                       transaction_id: u32,
+                       resp: &mut Vec<u8>)
+                       -> Result<(), ErrorResponse> {
+        if body.len() < 82 {
+            return Err(ErrorResponse::BadRequest);
+        }
+        let info_hash = try!(InfoHash::from_bytes(&body[0..20]).ok_or(ErrorResponse::BadRequest));
+        let peer_id = try!(PeerId::from_bytes(&body[20..40]).ok_or(ErrorResponse::BadRequest));
+        let dl = read_u64(&body[40..48]);
+        let left = read_u64(&body[48..56]);
+        let ul = read_u64(&body[56..64]);
+        let event = match read_u32(&body[64..68]) {
+            1 => Some("completed"),
+            2 => Some("started"),
+            3 => Some("stopped"),
+            _ => None,
+        };
+        // The IP address field is ignored, peers are always announced with their source address.
+        let key = read_u32(&body[72..76]);
+        let numwant = match read_u32(&body[76..80]) as i32 {
+            n if n <= 0 => 25,
+            n => cmp::min(n, 25) as u8,
+        };
+        let port = read_u16(&body[80..82]);
+        let (ipv4, ipv6) = match addr {
+            SocketAddr::V4(v4) => (Some(SocketAddrV4::new(*v4.ip(), port)), None),
+            SocketAddr::V6(v6) => (None, Some(SocketAddrV6::new(*v6.ip(), port, 0, 0))),
+        };
+
+        let announce = Announce {
+            info_hash: info_hash,
+            peer_id: peer_id,
+            passkey: None,
+            key: Some(format!("{:08x}", key)),
+            ipv4: ipv4,
+            ipv6: ipv6,
+            ul: ul,
+            dl: dl,
+            left: left,
+            action: Action::from_event(event, left),
+            numwant: numwant,
+            compact: true,
+        };
+        let a = match try!(self.tracker.handle_announce(announce)) {
+            SuccessResponse::Announce(a) => a,
+            _ => return Err(ErrorResponse::BadRequest),
+        };
+
+        write_u32(resp, ACTION_ANNOUNCE);
+        write_u32(resp, transaction_id);
+        write_u32(resp, a.announce_int.as_secs() as u32);
+        write_u32(resp, a.stats().incomplete as u32);
+        write_u32(resp, a.stats().complete as u32);
+        // As per BEP 15, IPv6 clients get IPv6 peers and IPv4 clients IPv4 peers.
+        match addr {
+            SocketAddr::V4(_) => resp.extend_from_slice(&a.peers().peers4),
+            SocketAddr::V6(_) => resp.extend_from_slice(&a.peers().peers6),
+        }
+        Ok(())
+    }
+
+    fn handle_scrape(&self,
+                     body: &[u8],
+                     transaction_id: u32,
+                     resp: &mut Vec<u8>)
+                     -> Result<(), ErrorResponse> {
+        if body.is_empty() || body.len() % 20 != 0 {
+            return Err(ErrorResponse::BadRequest);
+        }
+        let hashes: Vec<_> = body.chunks(20).filter_map(InfoHash::from_bytes).collect();
+        let s = match try!(self.tracker.handle_scrape(Scrape::new(hashes.clone()))) {
+            SuccessResponse::Scrape(s) => s,
+            _ => return Err(ErrorResponse::BadRequest),
+        };
+
+        write_u32(resp, ACTION_SCRAPE);
+        write_u32(resp, transaction_id);
+        for hash in hashes.iter() {
+            let (complete, downloaded, incomplete) = match s.torrents.get(hash) {
+                Some(stats) => (stats.complete, stats.downloaded, stats.incomplete),
+                None => (0, 0, 0),
+            };
+            write_u32(resp, complete as u32);
+            write_u32(resp, downloaded as u32);
+            write_u32(resp, incomplete as u32);
+        }
+        Ok(())
+    }
+
+    fn connection_id(&self, addr: &SocketAddr, windows_ago: u64) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut hasher = self.secret.build_hasher();
+        addr.hash(&mut hasher);
+        (now / CONNECTION_WINDOW - windows_ago).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn is_connected(&self, connection_id: u64, addr: &SocketAddr) -> bool {
+        connection_id == self.connection_id(addr, 0) || connection_id == self.connection_id(addr, 1)
+    }
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    ((read_u32(&b[0..4]) as u64) << 32) | read_u32(&b[4..8]) as u64
+}
+
+fn write_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+fn write_u64(buf: &mut Vec<u8>, v: u64) {
+    write_u32(buf, (v >> 32) as u32);
+    write_u32(buf, v as u32);
+}
+
+#[test]
+fn connect_then_announce() {
+    let tracker = Arc::new(Tracker::default());
+    let handler = UdpHandler::new(tracker, UdpSocket::bind("127.0.0.1:0").unwrap());
+    let addr = "127.0.0.1:6881".parse().unwrap();
+
+    let mut req = Vec::new();
+    write_u64(&mut req, PROTOCOL_ID);
+    write_u32(&mut req, ACTION_CONNECT);
+    write_u32(&mut req, 7);
+    let mut resp = Vec::new();
+    handler.handle_packet(&req, addr, &mut resp);
+    assert!(resp.len() == 16);
+    assert!(read_u32(&resp[4..8]) == 7);
+    let connection_id = read_u64(&resp[8..16]);
+
+    let mut req = Vec::new();
+    write_u64(&mut req, connection_id);
+    write_u32(&mut req, ACTION_ANNOUNCE);
+    write_u32(&mut req, 8);
+    req.extend_from_slice(&[1; 20]);
+    req.extend_from_slice(b"-UT3500-000000000000");
+    write_u64(&mut req, 0);
+    write_u64(&mut req, 0);
+    write_u64(&mut req, 0);
+    write_u32(&mut req, 2);
+    write_u32(&mut req, 0);
+    write_u32(&mut req, 42);
+    write_u32(&mut req, -1i32 as u32);
+    req.extend_from_slice(&[0x1a, 0xe1]);
+    let mut resp = Vec::new();
+    handler.handle_packet(&req, addr, &mut resp);
+    assert!(read_u32(&resp[0..4]) == ACTION_ANNOUNCE);
+    assert!(read_u32(&resp[4..8]) == 8);
+    assert!(read_u32(&resp[16..20]) == 1);
+
+    let mut req = Vec::new();
+    write_u64(&mut req, connection_id ^ 1);
+    write_u32(&mut req, ACTION_SCRAPE);
+    write_u32(&mut req, 9);
+    req.extend_from_slice(&[1; 20]);
+    let mut resp = Vec::new();
+    handler.handle_packet(&req, addr, &mut resp);
+    assert!(read_u32(&resp[0..4]) == ACTION_ERROR);
+}
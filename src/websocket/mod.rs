@@ -0,0 +1,414 @@
+use response::error::ErrorResponse;
+use response::success::SuccessResponse;
+use tracker::Tracker;
+use tracker::announce::{Action, Announce};
+use tracker::info_hash::InfoHash;
+use tracker::peer_id::PeerId;
+use config::WebSocketConfig;
+
+use ws::{self, CloseCode, Factory, Handler, Message, Sender};
+use rustc_serialize::json::{Json, Object};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::cmp;
+
+/// Connections of all WebTorrent peers, indexed by peer id, used to relay signaling messages.
+type Clients = Arc<Mutex<HashMap<PeerId, Sender>>>;
+
+/// Handles a single WebTorrent connection. Announces are stored in the regular swarms, with
+/// the peers having no address since they can only be reached through WebRTC, while SDP
+/// offers and answers are forwarded between the connections of the peers involved.
+pub struct WebSocketHandler {
+    tracker: Arc<Tracker>,
+    clients: Clients,
+    out: Sender,
+    swarms: Vec<Membership>,
+}
+
+/// A swarm the connection announced a peer in, with the counters of its latest announce so
+/// that the stop sent when the connection closes doesn't look like they went backwards.
+struct Membership {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    ul: u64,
+    dl: u64,
+    left: u64,
+}
+
+/// Creates the handlers of new connections, which all share the connections of the peers.
+struct HandlerFactory {
+    tracker: Arc<Tracker>,
+    clients: Clients,
+}
+
+impl Factory for HandlerFactory {
+    type Handler = WebSocketHandler;
+
+    fn connection_made(&mut self, out: Sender) -> WebSocketHandler {
+        WebSocketHandler {
+            tracker: self.tracker.clone(),
+            clients: self.clients.clone(),
+            out: out,
+            swarms: Vec::new(),
+        }
+    }
+}
+
+impl Handler for WebSocketHandler {
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let resp = match msg.as_text().ok().and_then(|text| Json::from_str(text).ok()) {
+            Some(json) => self.handle_json(&json),
+            None => Err(ErrorResponse::BadRequest),
+        };
+        match resp {
+            Ok(Some(json)) => self.out.send(json.to_string()),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                let mut failure = Object::new();
+                failure.insert(String::from("failure reason"),
+                               Json::String(String::from(e.reason())));
+                self.out.send(Json::Object(failure).to_string())
+            }
+        }
+    }
+
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+        let swarms = self.swarms.drain(..).collect::<Vec<_>>();
+        for membership in swarms {
+            let announce = self.stop_announce(&membership);
+            let _ = self.tracker.handle_announce(announce);
+            let mut clients = self.clients.lock();
+            let peer_id = membership.peer_id;
+            let owned = clients.get(&peer_id)
+                .is_some_and(|out| out.connection_id() == self.out.connection_id());
+            if owned {
+                clients.remove(&peer_id);
+            }
+        }
+    }
+}
+
+impl WebSocketHandler {
+    pub fn start(tracker: Arc<Tracker>, config: WebSocketConfig) -> io::Result<()> {
+        // Announces without passkeys would get around every rule of the private swarms.
+        if cfg!(feature = "private") {
+            error!("WebSocket listener {} can't authenticate passkeys, not starting it!",
+                   config.listen_addr);
+            return Ok(());
+        }
+        let socket = try!(WebSocketHandler::bind(tracker, &config.listen_addr));
+        info!("WebSocket interface listening on {}!", config.listen_addr);
+        try!(socket.run().map_err(to_io_error));
+        Ok(())
+    }
+
+    fn bind(tracker: Arc<Tracker>, addr: &str) -> io::Result<ws::WebSocket<HandlerFactory>> {
+        let factory = HandlerFactory {
+            tracker: tracker,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let socket = try!(ws::WebSocket::new(factory).map_err(to_io_error));
+        socket.bind(addr).map_err(to_io_error)
+    }
+
+    fn handle_json(&mut self, msg: &Json) -> Result<Option<Json>, ErrorResponse> {
+        match msg.find("action").and_then(|a| a.as_string()) {
+            Some("announce") => {
+                if msg.find("answer").is_some() {
+                    try!(self.forward_answer(msg));
+                    Ok(None)
+                } else {
+                    self.handle_announce(msg).map(Some)
+                }
+            }
+            _ => Err(ErrorResponse::BadAction),
+        }
+    }
+
+    fn handle_announce(&mut self, msg: &Json) -> Result<Json, ErrorResponse> {
+        let hash_str = try!(get_str(msg, "info_hash"));
+        let info_hash = try!(InfoHash::from_bytes(&try!(from_binary_str(hash_str)))
+            .ok_or(ErrorResponse::BadRequest));
+        let peer_id = try!(get_peer_id(msg, "peer_id"));
+        // Clients without the torrent's metadata yet can't know how much is left.
+        let left = msg.find("left").and_then(|v| v.as_u64()).unwrap_or(u64::MAX);
+        let action = Action::from_event(msg.find("event").and_then(|e| e.as_string()), left);
+        let offers = msg.find("offers").and_then(|o| o.as_array()).map_or(&[][..], |o| &o[..]);
+        let numwant = cmp::min(msg.find("numwant").and_then(|n| n.as_u64()).unwrap_or(0),
+                               cmp::min(offers.len() as u64, 25));
+
+        let ul = msg.find("uploaded").and_then(|v| v.as_u64()).unwrap_or(0);
+        let dl = msg.find("downloaded").and_then(|v| v.as_u64()).unwrap_or(0);
+        let announce = Announce {
+            info_hash: info_hash,
+            peer_id: peer_id,
+            passkey: None,
+            key: Some(self.key()),
+            ipv4: None,
+            ipv6: None,
+            ul: ul,
+            dl: dl,
+            left: left,
+            action: action.clone(),
+            numwant: numwant as u8,
+            compact: true,
+        };
+        let resp = match try!(self.tracker.handle_announce(announce)) {
+            SuccessResponse::Announce(a) => a,
+            _ => return Err(ErrorResponse::BadRequest),
+        };
+
+        match action {
+            Action::Stopped => {
+                self.swarms.retain(|m| m.info_hash != info_hash || m.peer_id != peer_id);
+            }
+            _ => {
+                let membership = Membership {
+                    info_hash: info_hash,
+                    peer_id: peer_id,
+                    ul: ul,
+                    dl: dl,
+                    left: left,
+                };
+                match self.swarms
+                    .iter_mut()
+                    .find(|m| m.info_hash == info_hash && m.peer_id == peer_id) {
+                    Some(m) => *m = membership,
+                    None => self.swarms.push(membership),
+                }
+                self.clients.lock().insert(peer_id, self.out.clone());
+                self.forward_offers(&info_hash, hash_str, &peer_id, offers, numwant as usize);
+            }
+        }
+
+        let mut json = Object::new();
+        json.insert(String::from("action"), Json::String(String::from("announce")));
+        json.insert(String::from("interval"),
+                    Json::U64(resp.announce_int.as_secs()));
+        json.insert(String::from("info_hash"), Json::String(String::from(hash_str)));
+        json.insert(String::from("complete"), Json::I64(resp.stats().complete));
+        json.insert(String::from("incomplete"), Json::I64(resp.stats().incomplete));
+        Ok(Json::Object(json))
+    }
+
+    fn forward_offers(&self,
+                      info_hash: &InfoHash,
+                      hash_str: &str,
+                      peer_id: &PeerId,
+                      offers: &[Json],
+                      amount: usize) {
+        let peers = self.tracker.get_webrtc_peers(info_hash, amount, peer_id);
+        let clients = self.clients.lock();
+        for (to, offer) in peers.iter().zip(offers.iter()) {
+            let out = match clients.get(to) {
+                Some(out) => out,
+                None => continue,
+            };
+            let mut json = Object::new();
+            json.insert(String::from("action"), Json::String(String::from("announce")));
+            json.insert(String::from("info_hash"), Json::String(String::from(hash_str)));
+            json.insert(String::from("peer_id"),
+                        Json::String(to_binary_str(peer_id.as_bytes())));
+            json.insert(String::from("offer"),
+                        offer.find("offer").cloned().unwrap_or(Json::Null));
+            json.insert(String::from("offer_id"),
+                        offer.find("offer_id").cloned().unwrap_or(Json::Null));
+            if out.send(Json::Object(json).to_string()).is_err() {
+                debug!("Failed to relay offer to peer {:?}", to);
+            }
+        }
+    }
+
+    /// Relays an answer to the peer whose offer it's for. Answers can only come from peers
+    /// announced on this connection.
+    fn forward_answer(&self, msg: &Json) -> Result<(), ErrorResponse> {
+        let hash_str = try!(get_str(msg, "info_hash"));
+        let info_hash = try!(InfoHash::from_bytes(&try!(from_binary_str(hash_str)))
+            .ok_or(ErrorResponse::BadRequest));
+        let peer_id = try!(get_peer_id(msg, "peer_id"));
+        let to_peer_id = try!(get_peer_id(msg, "to_peer_id"));
+        if !self.swarms.iter().any(|m| m.info_hash == info_hash && m.peer_id == peer_id) {
+            return Err(ErrorResponse::BadKey);
+        }
+        let clients = self.clients.lock();
+        let out = try!(clients.get(&to_peer_id).ok_or(ErrorResponse::BadPeer));
+
+        let mut json = Object::new();
+        json.insert(String::from("action"), Json::String(String::from("announce")));
+        json.insert(String::from("info_hash"), Json::String(String::from(hash_str)));
+        json.insert(String::from("peer_id"),
+                    Json::String(to_binary_str(peer_id.as_bytes())));
+        json.insert(String::from("answer"),
+                    msg.find("answer").cloned().unwrap_or(Json::Null));
+        json.insert(String::from("offer_id"),
+                    msg.find("offer_id").cloned().unwrap_or(Json::Null));
+        if out.send(Json::Object(json).to_string()).is_err() {
+            debug!("Failed to relay answer to peer {:?}", to_peer_id);
+        }
+        Ok(())
+    }
+
+    /// Peers are tied to the connection they announced on, so other connections can't act on
+    /// their behalf.
+    fn key(&self) -> String {
+        format!("ws:{}", self.out.connection_id())
+    }
+
+    fn stop_announce(&self, membership: &Membership) -> Announce {
+        Announce {
+            info_hash: membership.info_hash,
+            peer_id: membership.peer_id,
+            passkey: None,
+            key: Some(self.key()),
+            ipv4: None,
+            ipv6: None,
+            ul: membership.ul,
+            dl: membership.dl,
+            left: membership.left,
+            action: Action::Stopped,
+            numwant: 0,
+            compact: true,
+        }
+    }
+}
+
+fn to_io_error(e: ws::Error) -> io::Error {
+    match e.kind {
+        ws::ErrorKind::Io(e) => e,
+        _ => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+fn get_str<'a>(msg: &'a Json, key: &str) -> Result<&'a str, ErrorResponse> {
+    msg.find(key).and_then(|v| v.as_string()).ok_or(ErrorResponse::BadRequest)
+}
+
+fn get_peer_id(msg: &Json, key: &str) -> Result<PeerId, ErrorResponse> {
+    let id = try!(from_binary_str(try!(get_str(msg, key))));
+    PeerId::from_bytes(&id).ok_or(ErrorResponse::BadRequest)
+}
+
+/// WebTorrent encodes binary values as strings with one character per byte.
+fn from_binary_str(s: &str) -> Result<Vec<u8>, ErrorResponse> {
+    let mut bytes = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        if c as u32 > 0xff {
+            return Err(ErrorResponse::BadRequest);
+        }
+        bytes.push(c as u32 as u8);
+    }
+    Ok(bytes)
+}
+
+fn to_binary_str(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+#[cfg(test)]
+struct TestClient {
+    out: Sender,
+    events: ::std::sync::mpsc::Sender<Result<Sender, Json>>,
+}
+
+#[cfg(test)]
+impl Handler for TestClient {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        let _ = self.events.send(Ok(self.out.clone()));
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let json = Json::from_str(msg.as_text().unwrap()).unwrap();
+        let _ = self.events.send(Err(json));
+        Ok(())
+    }
+}
+
+#[test]
+fn offers_answers_and_cleanup() {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let tracker = Arc::new(Tracker::default());
+    let socket = WebSocketHandler::bind(tracker.clone(), "127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", socket.local_addr().unwrap());
+    thread::spawn(move || {
+        let _ = socket.run();
+    });
+    // Connects a client, returning its connection and the messages it receives.
+    let connect = || {
+        let (tx, rx) = mpsc::channel();
+        let url = url.clone();
+        thread::spawn(move || {
+            let _ = ws::connect(url, |out| {
+                TestClient {
+                    out: out,
+                    events: tx.clone(),
+                }
+            });
+        });
+        match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Ok(out) => (out, rx),
+            Err(json) => panic!("unexpected message {}", json),
+        }
+    };
+    let recv = |rx: &mpsc::Receiver<Result<Sender, Json>>| {
+        match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Ok(_) => panic!("unexpected connection"),
+            Err(json) => json,
+        }
+    };
+    let hash = "aaaaaaaaaaaaaaaaaaaa";
+    let announce = |peer: &str, offer: &str| {
+        format!("{{\"action\":\"announce\",\"info_hash\":\"{0}\",\"peer_id\":\"{1}\",\
+                 \"uploaded\":100,\"numwant\":1,\"offers\":[{{\"offer\":\"{2}\",\
+                 \"offer_id\":\"{2}\"}}]}}",
+                hash,
+                peer,
+                offer)
+    };
+    let (a, a_rx) = connect();
+    let (b, b_rx) = connect();
+    let a_id = "AAAAAAAAAAAAAAAAAAAA";
+    let b_id = "BBBBBBBBBBBBBBBBBBBB";
+
+    a.send(announce(a_id, "a")).unwrap();
+    assert!(recv(&a_rx).find("incomplete").and_then(|v| v.as_i64()) == Some(1));
+    // The second peer's offer goes to the first, whose answer goes back.
+    b.send(announce(b_id, "b")).unwrap();
+    assert!(recv(&b_rx).find("incomplete").and_then(|v| v.as_i64()) == Some(2));
+    let offer = recv(&a_rx);
+    assert!(offer.find("offer").and_then(|v| v.as_string()) == Some("b"));
+    assert!(offer.find("peer_id").and_then(|v| v.as_string()) == Some(b_id));
+    a.send(format!("{{\"action\":\"announce\",\"info_hash\":\"{}\",\"peer_id\":\"{}\",\
+                    \"to_peer_id\":\"{}\",\"answer\":\"answer\",\"offer_id\":\"b\"}}",
+                   hash,
+                   a_id,
+                   b_id))
+        .unwrap();
+    let answer = recv(&b_rx);
+    assert!(answer.find("answer").and_then(|v| v.as_string()) == Some("answer"));
+    // Other connections can't answer in the first peer's name.
+    b.send(format!("{{\"action\":\"announce\",\"info_hash\":\"{}\",\"peer_id\":\"{}\",\
+                    \"to_peer_id\":\"{}\",\"answer\":\"forged\",\"offer_id\":\"b\"}}",
+                   hash,
+                   a_id,
+                   b_id))
+        .unwrap();
+    assert!(recv(&b_rx).find("failure reason").is_some());
+    assert!(answer.find("peer_id").and_then(|v| v.as_string()) == Some(a_id));
+
+    // Closing the connection takes its peer out of the swarm.
+    let info_hash = InfoHash::from_bytes(hash.as_bytes()).unwrap();
+    let b_peer = PeerId::from_bytes(b_id.as_bytes()).unwrap();
+    a.close(CloseCode::Normal).unwrap();
+    let start = Instant::now();
+    while !tracker.get_webrtc_peers(&info_hash, 5, &b_peer).is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(tracker.get_torrent(&info_hash).unwrap().stats.incomplete == 1);
+}